@@ -0,0 +1,199 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use tracing::error;
+
+use crate::game::unix_now;
+
+pub const MIN_PASSWORD_LENGTH: usize = 6;
+const INITIAL_RATING: f64 = 1500.0;
+const K_FACTOR: f64 = 32.0;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Rating {
+    pub rating: f64,
+    pub games: u32,
+}
+
+impl Default for Rating {
+    fn default() -> Self {
+        Self {
+            rating: INITIAL_RATING,
+            games: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Account {
+    pub username: String,
+    pub password_hash: String,
+    pub created_at: u64,
+    #[serde(default)]
+    pub mouse_rating: Rating,
+    #[serde(default)]
+    pub trapper_rating: Rating,
+    /// Set by an admin; banned accounts cannot log in.
+    #[serde(default)]
+    pub banned: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LeaderboardEntry {
+    pub username: String,
+    pub rating: f64,
+    pub games: u32,
+}
+
+/// Registered accounts, kept in memory and written back to a JSON file
+/// after every change.
+pub struct AccountStore {
+    path: PathBuf,
+    accounts: Vec<Account>,
+}
+
+impl AccountStore {
+    pub fn load(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let accounts = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path, accounts })
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        let serialized = serde_json::to_string_pretty(&self.accounts)?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, serialized)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+
+    pub fn find(&self, username: &str) -> Option<&Account> {
+        self.accounts
+            .iter()
+            .find(|account| account.username == username)
+    }
+
+    pub fn register(&mut self, username: &str, password_hash: String) -> Result<(), String> {
+        if self.find(username).is_some() {
+            return Err("username already taken".to_string());
+        }
+        self.accounts.push(Account {
+            username: username.to_string(),
+            password_hash,
+            created_at: unix_now(),
+            mouse_rating: Rating::default(),
+            trapper_rating: Rating::default(),
+            banned: false,
+        });
+        if let Err(e) = self.save() {
+            self.accounts.retain(|account| account.username != username);
+            error!(error = %e, "could not save accounts");
+            return Err("could not save the account".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn set_banned(&mut self, username: &str, banned: bool) -> Result<(), String> {
+        let account = self
+            .accounts
+            .iter_mut()
+            .find(|account| account.username == username)
+            .ok_or_else(|| format!("no account {}", username))?;
+        account.banned = banned;
+        self.save().map_err(|e| format!("could not save accounts {}", e))
+    }
+
+    /// Elo update after a rated game. The mouse player's mouse rating is
+    /// played against the trapper player's trapper rating.
+    pub fn record_rated_game(&mut self, mouse: &str, trapper: &str, mouse_won: bool) {
+        let (Some(mouse_rating), Some(trapper_rating)) = (
+            self.find(mouse).map(|account| account.mouse_rating.rating),
+            self.find(trapper).map(|account| account.trapper_rating.rating),
+        ) else {
+            return;
+        };
+
+        let expected_mouse = 1.0 / (1.0 + 10f64.powf((trapper_rating - mouse_rating) / 400.0));
+        let score_mouse = if mouse_won { 1.0 } else { 0.0 };
+        let change = K_FACTOR * (score_mouse - expected_mouse);
+
+        for account in self.accounts.iter_mut() {
+            if account.username == mouse {
+                account.mouse_rating.rating += change;
+                account.mouse_rating.games += 1;
+            }
+            if account.username == trapper {
+                account.trapper_rating.rating -= change;
+                account.trapper_rating.games += 1;
+            }
+        }
+        if let Err(e) = self.save() {
+            error!(error = %e, "could not save accounts");
+        }
+    }
+
+    pub fn leaderboard(&self, mouse: bool, limit: usize) -> Vec<LeaderboardEntry> {
+        let mut entries: Vec<LeaderboardEntry> = self
+            .accounts
+            .iter()
+            .map(|account| {
+                let rating = if mouse {
+                    &account.mouse_rating
+                } else {
+                    &account.trapper_rating
+                };
+                LeaderboardEntry {
+                    username: account.username.clone(),
+                    rating: rating.rating,
+                    games: rating.games,
+                }
+            })
+            .filter(|entry| entry.games > 0)
+            .collect();
+        entries.sort_by(|a, b| b.rating.total_cmp(&a.rating));
+        entries.truncate(limit);
+        entries
+    }
+}
+
+pub fn validate_username(username: &str) -> Result<(), String> {
+    if username.len() < 3 || username.len() > 20 {
+        Err("username must be 3 to 20 characters".to_string())
+    } else if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        Err("username may only contain letters, digits, '_' and '-'".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String, String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "password must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        ));
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("could not hash password {}", e))
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,362 @@
+//! The operators' console: a line based protocol on its own listener, meant
+//! to be used with `nc` from the machine the server runs on. The first line
+//! has to be `auth <token>`; after that every command is answered with any
+//! number of plain lines followed by one `OK:` or `ERR:` line.
+
+use std::sync::Arc;
+
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::game::{unix_now, GameState, Room, RoomType, MAX_CHAT_LENGTH};
+use crate::metrics::METRICS;
+use crate::{reply_line, Announcement, Changes, Server};
+
+const HELP: &[&str] = &[
+    "rooms                 list every room",
+    "connections           list connections and who is logged in on them",
+    "room ID               show a room as JSON",
+    "end ID                end the game in a room without a winner (not archived)",
+    "delete ID             remove a room",
+    "kick USER             close every connection of USER",
+    "ban USER              stop USER from logging in and kick them",
+    "unban USER            let USER log in again",
+    "announce [TEXT]       show TEXT to everyone; without TEXT, clear it",
+    "snapshot              write a snapshot now",
+    "quit                  close the console",
+];
+
+fn state_name(room: &Room) -> &'static str {
+    match room.game_state {
+        GameState::Waiting => "waiting",
+        GameState::InGame => "in_game",
+        GameState::GameOver => "game_over",
+    }
+}
+
+fn room_line(room: &Room) -> String {
+    let kind = match room.room_type {
+        RoomType::SinglePlayer => "single",
+        RoomType::MultiPlayer => "multi",
+    };
+    format!(
+        "{} {} {} {} mouse={} trapper={} spectators={}{}",
+        room.room_id,
+        room.room_name,
+        kind,
+        state_name(room),
+        room.mouse_player.as_deref().unwrap_or("-"),
+        room.trapper_player.as_deref().unwrap_or("-"),
+        room.spectators.len(),
+        if room.locked { " private" } else { "" },
+    )
+}
+
+fn parse_room_id(argument: &str) -> Result<u32, String> {
+    argument
+        .parse()
+        .map_err(|_| format!("'{}' is not a room id", argument))
+}
+
+impl Server {
+    fn connection_lines(&self) -> Vec<String> {
+        match self.lobby.lock() {
+            Ok(lobby) => lobby
+                .sessions
+                .iter()
+                .map(|session| {
+                    format!(
+                        "{} {}",
+                        session.connection_id,
+                        session.username.as_deref().unwrap_or("-")
+                    )
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Closes every connection logged in as `username` and returns how many.
+    /// Each connection task then disconnects as usual, so a game in progress
+    /// is lost by disconnect once the reconnect grace is over.
+    fn kick(&self, username: &str) -> usize {
+        let Ok(lobby) = self.lobby.lock() else {
+            return 0;
+        };
+        let sessions: Vec<_> = lobby
+            .sessions
+            .iter()
+            .filter(|session| session.username.as_deref() == Some(username))
+            .collect();
+        for session in &sessions {
+            session.kick.notify_one();
+        }
+        sessions.len()
+    }
+
+    fn end_game(&self, room_id: u32) -> Result<(), String> {
+        let ended = self
+            .with_room(room_id, |room| {
+                if room.game_state == GameState::GameOver {
+                    return false;
+                }
+                if room.game_state == GameState::InGame {
+                    METRICS.game_finished(None);
+                }
+                room.game_state = GameState::GameOver;
+                room.winner = None;
+                true
+            })
+            .ok_or_else(|| format!("no room {}", room_id))?;
+        if !ended {
+            return Err(format!("room {} has no game to end", room_id));
+        }
+        let changes = Changes {
+            rooms: vec![room_id],
+            ..Default::default()
+        };
+        self.commit(&format!("admin end {}", room_id), changes);
+        Ok(())
+    }
+
+    fn delete_room(&self, room_id: u32) -> Result<(), String> {
+        {
+            let mut lobby = self
+                .lobby
+                .lock()
+                .map_err(|_| "lobby unavailable".to_string())?;
+            if self.remove_rooms(|room| room.room_id == room_id).is_empty() {
+                return Err(format!("no room {}", room_id));
+            }
+            lobby.room_codes.remove(&room_id);
+        }
+        let changes = Changes {
+            rooms: vec![room_id],
+            room_codes: true,
+            ..Default::default()
+        };
+        self.commit(&format!("admin delete {}", room_id), changes);
+        Ok(())
+    }
+
+    fn announce(&self, text: &str) -> Result<String, String> {
+        let text: String = text.chars().filter(|c| !c.is_control()).collect();
+        let text = text.trim();
+        if text.chars().count() > MAX_CHAT_LENGTH {
+            return Err(format!(
+                "announcement longer than {} characters",
+                MAX_CHAT_LENGTH
+            ));
+        }
+        let mut lobby = self
+            .lobby
+            .lock()
+            .map_err(|_| "lobby unavailable".to_string())?;
+        if text.is_empty() {
+            lobby.announcement = None;
+            Ok("announcement cleared".to_string())
+        } else {
+            lobby.announcement = Some(Announcement {
+                text: text.to_string(),
+                sent_at: unix_now(),
+            });
+            Ok("announcement sent".to_string())
+        }
+    }
+
+    /// Runs the part of a console command that writes files on the blocking
+    /// pool.
+    async fn blocking<T: Send + 'static>(
+        self: &Arc<Self>,
+        run: impl FnOnce(&Server) -> Result<T, String> + Send + 'static,
+    ) -> Result<T, String> {
+        let server = Arc::clone(self);
+        tokio::task::spawn_blocking(move || run(&server))
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()))
+    }
+
+    /// Runs one console command: what it printed, then its result.
+    async fn admin_command(self: &Arc<Self>, line: &str) -> (Vec<String>, Result<String, String>) {
+        let (command, argument) = match line.split_once(' ') {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+        match command {
+            "help" => (
+                HELP.iter().map(|line| line.to_string()).collect(),
+                Ok("help".to_string()),
+            ),
+            "rooms" => {
+                let lines: Vec<String> = self.all_rooms().iter().map(room_line).collect();
+                let count = format!("{} rooms", lines.len());
+                (lines, Ok(count))
+            }
+            "connections" => {
+                let lines = self.connection_lines();
+                let count = format!("{} connections", lines.len());
+                (lines, Ok(count))
+            }
+            "room" => {
+                let result = parse_room_id(argument).and_then(|room_id| {
+                    let room = self
+                        .room(room_id)
+                        .and_then(|room| room.lock().ok().map(|room| room.clone()))
+                        .ok_or_else(|| format!("no room {}", room_id))?;
+                    serde_json::to_string_pretty(&room).map_err(|e| e.to_string())
+                });
+                match result {
+                    Ok(json) => (
+                        json.lines().map(|line| line.to_string()).collect(),
+                        Ok(format!("room {}", argument)),
+                    ),
+                    Err(e) => (Vec::new(), Err(e)),
+                }
+            }
+            "end" => {
+                let result = match parse_room_id(argument) {
+                    Ok(room_id) => self.blocking(move |server| server.end_game(room_id)).await,
+                    Err(e) => Err(e),
+                };
+                (
+                    Vec::new(),
+                    result.map(|_| format!("ended room {}", argument)),
+                )
+            }
+            "delete" => {
+                let result = match parse_room_id(argument) {
+                    Ok(room_id) => {
+                        self.blocking(move |server| server.delete_room(room_id))
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                (
+                    Vec::new(),
+                    result.map(|_| format!("deleted room {}", argument)),
+                )
+            }
+            "kick" if !argument.is_empty() => (
+                Vec::new(),
+                Ok(format!("kicked {} connections", self.kick(argument))),
+            ),
+            "ban" | "unban" if !argument.is_empty() => {
+                let banned = command == "ban";
+                let username = argument.to_string();
+                let result = self
+                    .blocking(move |server| {
+                        server
+                            .accounts
+                            .lock()
+                            .map_err(|_| "accounts unavailable".to_string())
+                            .and_then(|mut accounts| accounts.set_banned(&username, banned))
+                    })
+                    .await;
+                match result {
+                    Ok(()) if banned => (
+                        Vec::new(),
+                        Ok(format!(
+                            "banned {}, kicked {} connections",
+                            argument,
+                            self.kick(argument)
+                        )),
+                    ),
+                    Ok(()) => (Vec::new(), Ok(format!("unbanned {}", argument))),
+                    Err(e) => (Vec::new(), Err(e)),
+                }
+            }
+            "announce" => (Vec::new(), self.announce(argument)),
+            "snapshot" => {
+                if !self.config.persistence.enabled {
+                    return (Vec::new(), Err("persistence is disabled".to_string()));
+                }
+                let server = Arc::clone(self);
+                match tokio::task::spawn_blocking(move || server.save_snapshot()).await {
+                    Ok(()) => (Vec::new(), Ok("snapshot saved".to_string())),
+                    Err(e) => (Vec::new(), Err(e.to_string())),
+                }
+            }
+            _ => (
+                Vec::new(),
+                Err(format!("unknown command '{}', try help", line)),
+            ),
+        }
+    }
+}
+
+async fn handle_admin(stream: TcpStream, server: Arc<Server>, token: Arc<String>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let authenticated = match lines.next_line().await {
+        // Compared in constant time, so how fast a guess is refused says
+        // nothing about how much of it was right.
+        Ok(Some(line)) => line
+            .trim()
+            .strip_prefix("auth ")
+            .is_some_and(|given| bool::from(given.as_bytes().ct_eq(token.as_bytes()))),
+        _ => false,
+    };
+    if !authenticated {
+        warn!("admin login failed");
+        let _ = writer
+            .write_all(reply_line(Err("authentication failed".to_string())).as_bytes())
+            .await;
+        return;
+    }
+    if writer
+        .write_all(reply_line(Ok("authenticated".to_string())).as_bytes())
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "quit" {
+            let _ = writer
+                .write_all(reply_line(Ok("bye".to_string())).as_bytes())
+                .await;
+            break;
+        }
+        let (output, result) = server.admin_command(line).await;
+        match &result {
+            Ok(_) => info!(command = line, "admin command"),
+            Err(e) => warn!(command = line, error = %e, "admin command failed"),
+        }
+        let mut reply = String::new();
+        for line in output {
+            reply.push_str(&line);
+            reply.push('\n');
+        }
+        reply.push_str(&reply_line(result));
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Accepts console connections, each on its own task.
+pub async fn serve(listener: TcpListener, server: Arc<Server>, token: String) {
+    let token = Arc::new(token);
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let span = info_span!("admin", %peer);
+                tokio::spawn(
+                    handle_admin(stream, Arc::clone(&server), Arc::clone(&token)).instrument(span),
+                );
+            }
+            Err(e) => {
+                error!(error = %e, "admin accept failed");
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
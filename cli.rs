@@ -0,0 +1,658 @@
+//! A terminal client for scripting and debugging. It talks to the server
+//! through the same connection code as the window client and runs either the
+//! one command given on the command line or, without one, every command read
+//! from stdin.
+
+#[allow(dead_code)]
+mod connection;
+#[allow(dead_code)]
+mod game;
+#[allow(dead_code)]
+mod protocol;
+mod tls_client;
+
+use connection::{Connection, ConnectionState, Event};
+use game::{GameState, Room, RoomType, Turn};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io::{BufRead, IsTerminal, Write};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const DEFAULT_SERVER_ADDRESS: &str = "127.0.0.1:8080";
+const PASSWORD_VARIABLE: &str = "TRAPTHEMOUSE_PASSWORD";
+const BOARD_SIZE: u32 = 11;
+const MAX_ROOM_CODE_LENGTH: usize = 32;
+const UPDATE_INTERVAL: Duration = Duration::from_millis(50);
+/// How many update polls a command waits for news before it is checked.
+const REFRESH_POLLS: u32 = 3;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long a command has to show up in the server's updates. Most commands
+/// get no reply, so this is the only way to tell they were refused.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(3);
+
+const HELP: &[&str] = &[
+    "login USER                       log in (or use --user)",
+    "rooms                            list every room",
+    "board ROOM                       show a room and its board",
+    "create NAME [CODE]               create a room, private when CODE is given",
+    "join ROOM mouse|trapper|spectator [CODE]",
+    "                                 take a seat in a room, or watch it",
+    "ready ROOM                       say you are ready; the game starts when both are",
+    "move ROOM X Y                    move the mouse or place a trap, by your seat",
+    "leave ROOM                       give up your seat, or stop watching",
+    "help                             show this",
+    "quit                             leave (interactive mode only)",
+];
+
+/// The part of the get_update reply this client reads.
+#[derive(Deserialize)]
+struct ServerView {
+    rooms: Vec<Room>,
+}
+
+struct Options {
+    address: String,
+    trusted_ca: String,
+    username: Option<String>,
+    json: bool,
+    command: Vec<String>,
+}
+
+enum Output {
+    Rooms(Vec<Room>),
+    Room(Box<Room>),
+    Message(String),
+    Lines(Vec<String>),
+}
+
+struct Session {
+    connection: Connection,
+    username: Option<String>,
+    rooms: Vec<Room>,
+}
+
+impl Session {
+    /// Connects and waits for the first update, so `rooms` is filled in.
+    fn open(options: &Options) -> Result<Self, String> {
+        let connection = Connection::open(
+            options.address.clone(),
+            options.trusted_ca.clone(),
+            UPDATE_INTERVAL,
+            Arc::new(|| {}),
+        );
+        let mut session = Self {
+            connection,
+            username: None,
+            rooms: Vec::new(),
+        };
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        loop {
+            match session.receive(deadline.saturating_duration_since(Instant::now()))? {
+                Some(true) => return Ok(session),
+                Some(false) => {}
+                None => return Err(format!("no answer from {}", options.address)),
+            }
+        }
+    }
+
+    /// Takes in the next thing the connection received: Some(true) when it
+    /// was an update, None when nothing came within `wait`. A command refused
+    /// by a limit or a lost connection is an error.
+    fn receive(&mut self, wait: Duration) -> Result<Option<bool>, String> {
+        match self.connection.events.recv_timeout(wait) {
+            Ok(Event::Update(update)) => {
+                let view: ServerView = serde_json::from_str(&update)
+                    .map_err(|e| format!("could not read the server update: {}", e))?;
+                self.rooms = view.rooms;
+                Ok(Some(true))
+            }
+            Ok(Event::State(ConnectionState::Failed(e))) => Err(e),
+            Ok(Event::Refused(reason)) => Err(reason),
+            Ok(Event::State(_) | Event::ServerHello(_)) => Ok(Some(false)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err("the connection is closed".to_string()),
+        }
+    }
+
+    /// Reads updates until `find` picks something out of one.
+    fn wait_for<T>(
+        &mut self,
+        timeout: Duration,
+        find: impl Fn(&[Room]) -> Option<T>,
+    ) -> Result<T, String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            match self.receive(wait)? {
+                Some(true) => {
+                    if let Some(found) = find(&self.rooms) {
+                        return Ok(found);
+                    }
+                }
+                Some(false) => {}
+                None => return Err("the server did not take the command".to_string()),
+            }
+        }
+    }
+
+    /// Takes in the updates received so far and any that come in over the
+    /// next few polls, so checks before a command see what other players
+    /// just did. The connection only reports updates that changed something.
+    fn refresh(&mut self) -> Result<(), String> {
+        let deadline = Instant::now() + UPDATE_INTERVAL * REFRESH_POLLS;
+        while self
+            .receive(deadline.saturating_duration_since(Instant::now()))?
+            .is_some()
+        {}
+        Ok(())
+    }
+
+    fn username(&self) -> Result<String, String> {
+        self.username
+            .clone()
+            .ok_or_else(|| "log in first".to_string())
+    }
+
+    fn room(&self, room_id: u32) -> Result<Room, String> {
+        self.rooms
+            .iter()
+            .find(|room| room.room_id == room_id)
+            .cloned()
+            .ok_or_else(|| format!("no room {}", room_id))
+    }
+
+    /// Sends a command the server does not answer, then waits for its effect.
+    fn send_and_wait(
+        &mut self,
+        command: &str,
+        find: impl Fn(&[Room]) -> Option<Room>,
+    ) -> Result<Output, String> {
+        self.connection.send(command);
+        self.wait_for(COMMAND_TIMEOUT, find)
+            .map(|room| Output::Room(Box::new(room)))
+    }
+
+    fn login(&mut self, username: &str, password: &str) -> Result<Output, String> {
+        let answer = self
+            .connection
+            .request(&format!("login {} {} ", username, password));
+        match answer.recv_timeout(CONNECT_TIMEOUT) {
+            Ok(result) => result?,
+            Err(_) => return Err("no answer from the server".to_string()),
+        };
+        self.username = Some(username.to_string());
+        Ok(Output::Message(format!("logged in as {}", username)))
+    }
+
+    fn create(&mut self, name: &str, code: Option<&str>) -> Result<Output, String> {
+        self.username()?;
+        if code.is_some_and(|code| code.len() > MAX_ROOM_CODE_LENGTH) {
+            return Err(format!(
+                "room codes can be at most {} characters",
+                MAX_ROOM_CODE_LENGTH
+            ));
+        }
+        let existing: HashSet<u32> = self.rooms.iter().map(|room| room.room_id).collect();
+        let command = match code {
+            Some(code) => format!("create_room {} code {} ", name, code),
+            None => format!("create_room {} ", name),
+        };
+        self.send_and_wait(&command, |rooms| {
+            rooms
+                .iter()
+                .find(|room| room.room_name == name && !existing.contains(&room.room_id))
+                .cloned()
+        })
+    }
+
+    fn join(&mut self, room_id: u32, role: &str, code: Option<&str>) -> Result<Output, String> {
+        let username = self.username()?;
+        if !matches!(role, "mouse" | "trapper" | "spectator") {
+            return Err(format!("'{}' is not mouse, trapper or spectator", role));
+        }
+        self.room(room_id)?;
+        let mut command = format!("join_room {} {} {} ", room_id, role, username);
+        if let Some(code) = code {
+            command.push_str(&format!("{} ", code));
+        }
+        self.send_and_wait(&command, |rooms| {
+            rooms
+                .iter()
+                .find(|room| room.room_id == room_id && seat(room, &username) == Some(role))
+                .cloned()
+        })
+    }
+
+    fn ready(&mut self, room_id: u32) -> Result<Output, String> {
+        let username = self.username()?;
+        let room = self.room(room_id)?;
+        if !matches!(seat(&room, &username), Some("mouse" | "trapper")) {
+            return Err(format!("you are not playing in room {}", room_id));
+        }
+        self.send_and_wait(&format!("ready {} ", room_id), |rooms| {
+            rooms
+                .iter()
+                .find(|room| {
+                    room.room_id == room_id
+                        && (room.game_state != GameState::Waiting
+                            || match seat(room, &username) {
+                                Some("mouse") => room.mouse_ready,
+                                Some("trapper") => room.trapper_ready,
+                                _ => false,
+                            })
+                })
+                .cloned()
+        })
+    }
+
+    /// Moves the mouse or places a trap, whichever the user's seat does. In a
+    /// single player room the user places traps and the AI mouse answers, as
+    /// in the window client. The server ends the game once the board shows a
+    /// winner.
+    fn make_move(&mut self, room_id: u32, x: u32, y: u32) -> Result<Output, String> {
+        let username = self.username()?;
+        let room = self.room(room_id)?;
+        if room.game_state != GameState::InGame {
+            return Err(format!("room {} has no game in progress", room_id));
+        }
+        // Single player rooms have no seats; the server only lets their
+        // owner play.
+        let seat = match room.room_type {
+            RoomType::SinglePlayer => Some("trapper"),
+            RoomType::MultiPlayer => seat(&room, &username),
+        };
+        let (command, turn) = match seat {
+            Some("mouse") => {
+                let (mouse_x, mouse_y) = room.mouse_position;
+                if !room.posib_moves(mouse_x, mouse_y).contains(&(x, y)) {
+                    return Err(format!("the mouse cannot move to {} {}", x, y));
+                }
+                ("move_mouse", Turn::MousePlayer)
+            }
+            Some("trapper") => {
+                if (x, y) == room.mouse_position || room.walls.contains(&(x, y)) {
+                    return Err(format!("{} {} is not free", x, y));
+                }
+                ("place_trap", Turn::TrapperPlayer)
+            }
+            _ => return Err(format!("you are not playing in room {}", room_id)),
+        };
+        if room.turn != turn {
+            return Err("it is not your turn".to_string());
+        }
+
+        let moves = room.moves.len();
+        let find_moves = |count: usize| {
+            move |rooms: &[Room]| {
+                rooms
+                    .iter()
+                    .find(|room| {
+                        room.room_id == room_id
+                            && (room.moves.len() >= count || room.game_state == GameState::GameOver)
+                    })
+                    .cloned()
+            }
+        };
+        self.connection
+            .send(&format!("{} {} {} {} ", command, room_id, x, y));
+        let mut room = self.wait_for(COMMAND_TIMEOUT, find_moves(moves + 1))?;
+        if room.room_type == RoomType::SinglePlayer && room.game_state == GameState::InGame {
+            self.connection.send(&format!("AI {} ", room_id));
+            room = self.wait_for(COMMAND_TIMEOUT, find_moves(moves + 2))?;
+        }
+        Ok(Output::Room(Box::new(room)))
+    }
+
+    /// Leaves a room. A player whose game has started leaves it the way the
+    /// window client does, which forfeits a game still being played.
+    fn leave(&mut self, room_id: u32) -> Result<Output, String> {
+        let username = self.username()?;
+        let room = self.room(room_id)?;
+        let role =
+            seat(&room, &username).ok_or_else(|| format!("you are not in room {}", room_id))?;
+        if room.game_state == GameState::Waiting || role == "spectator" {
+            self.connection
+                .send(&format!("exit_room {} {} ", room_id, username));
+            self.wait_for(COMMAND_TIMEOUT, |rooms| {
+                rooms
+                    .iter()
+                    .find(|room| room.room_id == room_id)
+                    .is_none_or(|room| seat(room, &username).is_none())
+                    .then_some(())
+            })?;
+        } else {
+            self.connection
+                .send(&format!("after_exit_room {} ", room_id));
+            self.wait_for(COMMAND_TIMEOUT, |rooms| {
+                rooms
+                    .iter()
+                    .find(|room| room.room_id == room_id)
+                    .is_none_or(|room| match role {
+                        "mouse" => room.mouse_player_exited,
+                        _ => room.trapper_player_exited,
+                    })
+                    .then_some(())
+            })?;
+        }
+        Ok(Output::Message(format!("left room {}", room_id)))
+    }
+
+    fn run(&mut self, words: &[&str]) -> Result<Output, String> {
+        self.refresh()?;
+        match words {
+            ["help"] => Ok(Output::Lines(
+                HELP.iter().map(|line| line.to_string()).collect(),
+            )),
+            ["login", username] => self.login(username, &read_password(username)?),
+            ["rooms"] => Ok(Output::Rooms(self.rooms.clone())),
+            ["board", room_id] => self
+                .room(parse_number(room_id)?)
+                .map(|room| Output::Room(Box::new(room))),
+            ["create", name] => self.create(name, None),
+            ["create", name, code] => self.create(name, Some(code)),
+            ["join", room_id, role] => self.join(parse_number(room_id)?, role, None),
+            ["join", room_id, role, code] => self.join(parse_number(room_id)?, role, Some(code)),
+            ["ready", room_id] => self.ready(parse_number(room_id)?),
+            ["move", room_id, x, y] => {
+                let (x, y) = (parse_coordinate(x)?, parse_coordinate(y)?);
+                self.make_move(parse_number(room_id)?, x, y)
+            }
+            ["leave", room_id] => self.leave(parse_number(room_id)?),
+            _ => Err(format!("unknown command '{}', try help", words.join(" "))),
+        }
+    }
+}
+
+/// Where `username` sits in `room`: mouse, trapper, spectator or nowhere.
+fn seat(room: &Room, username: &str) -> Option<&'static str> {
+    if room.mouse_player.as_deref() == Some(username) {
+        Some("mouse")
+    } else if room.trapper_player.as_deref() == Some(username) {
+        Some("trapper")
+    } else if room
+        .spectators
+        .iter()
+        .any(|spectator| spectator == username)
+    {
+        Some("spectator")
+    } else {
+        None
+    }
+}
+
+fn parse_number(text: &str) -> Result<u32, String> {
+    text.parse()
+        .map_err(|_| format!("'{}' is not a number", text))
+}
+
+fn parse_coordinate(text: &str) -> Result<u32, String> {
+    match parse_number(text)? {
+        coordinate if coordinate < BOARD_SIZE => Ok(coordinate),
+        _ => Err(format!("coordinates go from 0 to {}", BOARD_SIZE - 1)),
+    }
+}
+
+fn state_name(room: &Room) -> &'static str {
+    match room.game_state {
+        GameState::Waiting => "waiting",
+        GameState::InGame => "in_game",
+        GameState::GameOver => "game_over",
+    }
+}
+
+fn room_line(room: &Room) -> String {
+    let kind = match room.room_type {
+        RoomType::SinglePlayer => "single",
+        RoomType::MultiPlayer => "multi",
+    };
+    format!(
+        "{} {} {} {} mouse={} trapper={} spectators={}{}",
+        room.room_id,
+        room.room_name,
+        kind,
+        state_name(room),
+        room.mouse_player.as_deref().unwrap_or("-"),
+        room.trapper_player.as_deref().unwrap_or("-"),
+        room.spectators.len(),
+        if room.locked { " private" } else { "" },
+    )
+}
+
+/// The board with rows top to bottom and odd rows shifted right, as the
+/// window client draws it: `M` is the mouse, `#` a trap and `.` a free hex.
+/// Rows are the X of a move, columns the Y.
+fn board_lines(room: &Room) -> Vec<String> {
+    let columns: String = (0..BOARD_SIZE).map(|y| format!("{:<2}", y)).collect();
+    let mut lines = vec![format!("   {}", columns.trim_end())];
+    for x in 0..BOARD_SIZE {
+        let mut line = format!("{:>2} ", x);
+        if x % 2 == 1 {
+            line.push(' ');
+        }
+        for y in 0..BOARD_SIZE {
+            let hex = if (x, y) == room.mouse_position {
+                'M'
+            } else if room.walls.contains(&(x, y)) {
+                '#'
+            } else {
+                '.'
+            };
+            line.push(hex);
+            line.push(' ');
+        }
+        lines.push(line.trim_end().to_string());
+    }
+    lines
+}
+
+fn status_line(room: &Room) -> String {
+    let role = |turn: &Turn| match turn {
+        Turn::MousePlayer => "mouse",
+        Turn::TrapperPlayer => "trapper",
+    };
+    match room.game_state {
+        GameState::Waiting => format!(
+            "waiting: mouse {}, trapper {}",
+            if room.mouse_ready {
+                "ready"
+            } else {
+                "not ready"
+            },
+            if room.trapper_ready {
+                "ready"
+            } else {
+                "not ready"
+            },
+        ),
+        GameState::InGame => format!("{} to move", role(&room.turn)),
+        GameState::GameOver => match &room.winner {
+            Some(winner) => format!("game over, the {} won", role(winner)),
+            None => "game over, no winner".to_string(),
+        },
+    }
+}
+
+fn print(output: Output, json: bool) {
+    match output {
+        Output::Rooms(rooms) if json => println!("{}", serde_json::to_string(&rooms).unwrap()),
+        Output::Room(room) if json => println!("{}", serde_json::to_string(&room).unwrap()),
+        Output::Message(message) if json => println!("{}", serde_json::json!({ "ok": message })),
+        Output::Lines(lines) if json => println!("{}", serde_json::to_string(&lines).unwrap()),
+        Output::Rooms(rooms) => {
+            for room in &rooms {
+                println!("{}", room_line(room));
+            }
+        }
+        Output::Room(room) => {
+            println!("{}", room_line(&room));
+            for line in board_lines(&room) {
+                println!("{}", line);
+            }
+            println!("{}", status_line(&room));
+        }
+        Output::Message(message) => println!("{}", message),
+        Output::Lines(lines) => {
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+fn print_error(error: &str, json: bool) {
+    if json {
+        println!("{}", serde_json::json!({ "error": error }));
+    } else {
+        eprintln!("ERR:{}", error);
+    }
+}
+
+fn usage() -> String {
+    let mut lines = vec![
+        "usage: cli [options] [command]".to_string(),
+        "  --server ADDR          host:port, or tls://host:port (default 127.0.0.1:8080)"
+            .to_string(),
+        "  --ca PATH              PEM file to check a TLS server against (default web roots)"
+            .to_string(),
+        "  --user NAME            log in as NAME first".to_string(),
+        "  --json                 print one JSON value per command".to_string(),
+        "".to_string(),
+        format!(
+            "Passwords come from ${}, or are asked for on the terminal.",
+            PASSWORD_VARIABLE
+        ),
+        "Runs the command and exits, or reads commands from stdin without one.".to_string(),
+        "Staying away past the server's reconnect grace forfeits a game in".to_string(),
+        "progress, so play games interactively or while logged in elsewhere.".to_string(),
+        "Commands:".to_string(),
+    ];
+    lines.extend(HELP.iter().map(|line| format!("  {}", line)));
+    lines.join("\n")
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        address: DEFAULT_SERVER_ADDRESS.to_string(),
+        trusted_ca: String::new(),
+        username: None,
+        json: false,
+        command: Vec::new(),
+    };
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut i = 0;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        match flag {
+            "--help" => return Err(usage()),
+            "--json" => {
+                options.json = true;
+                i += 1;
+                continue;
+            }
+            _ if !flag.starts_with("--") => {
+                options.command = args[i..].to_vec();
+                break;
+            }
+            _ => {}
+        }
+        let value = args
+            .get(i + 1)
+            .ok_or_else(|| format!("missing value for {}\n{}", flag, usage()))?;
+        match flag {
+            "--server" => options.address = value.clone(),
+            "--ca" => options.trusted_ca = value.clone(),
+            "--user" => options.username = Some(value.clone()),
+            _ => return Err(format!("unknown option '{}'\n{}", flag, usage())),
+        }
+        i += 2;
+    }
+    Ok(options)
+}
+
+/// The password for `username`: $TRAPTHEMOUSE_PASSWORD, or asked for on the
+/// terminal without echoing it. Passwords are never taken from the command
+/// line, where anyone on the machine can read them in the process list.
+fn read_password(username: &str) -> Result<String, String> {
+    if let Ok(password) = std::env::var(PASSWORD_VARIABLE) {
+        return Ok(password);
+    }
+    rpassword::prompt_password(format!("Password for {}: ", username)).map_err(|e| {
+        format!(
+            "could not read the password ({}); set ${}",
+            e, PASSWORD_VARIABLE
+        )
+    })
+}
+
+fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // Asked for before connecting, so the server does not wait on the user.
+    let login = match &options.username {
+        Some(username) => match read_password(username) {
+            Ok(password) => Some((username, password)),
+            Err(e) => {
+                print_error(&e, options.json);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut session = match Session::open(&options) {
+        Ok(session) => session,
+        Err(e) => {
+            print_error(&e, options.json);
+            std::process::exit(1);
+        }
+    };
+    if let Some((username, password)) = &login {
+        if let Err(e) = session.login(username, password) {
+            print_error(&e, options.json);
+            std::process::exit(1);
+        }
+    }
+
+    if !options.command.is_empty() {
+        let words: Vec<&str> = options.command.iter().map(String::as_str).collect();
+        match session.run(&words) {
+            Ok(output) => print(output, options.json),
+            Err(e) => {
+                print_error(&e, options.json);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let prompt = std::io::stdin().is_terminal() && !options.json;
+    loop {
+        if prompt {
+            print!("> ");
+            let _ = std::io::stdout().flush();
+        }
+        let mut line = String::new();
+        match std::io::stdin().lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [] => continue,
+            ["quit"] | ["exit"] => break,
+            _ => match session.run(&words) {
+                Ok(output) => print(output, options.json),
+                Err(e) => print_error(&e, options.json),
+            },
+        }
+    }
+}
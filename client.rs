@@ -1,57 +1,201 @@
+mod connection;
+#[allow(dead_code)]
+mod game;
+mod logging;
+#[allow(dead_code)]
+mod protocol;
+#[cfg(not(target_arch = "wasm32"))]
+mod tls_client;
+
+use connection::{Connection, ConnectionState, Event};
+use game::{BoardConfig, ChatMessage, GameMove, GameState, Room, RoomType, Turn};
+use logging::{LogFormat, LogLevel};
+use protocol::Hello;
 use egui::{Button, Color32, Frame};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::process::exit;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::Arc;
+use tracing::{debug, warn};
+use web_time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(not(target_arch = "wasm32"))]
+const DEFAULT_SERVER_ADDRESS: &str = "127.0.0.1:8080";
+/// The browser build connects to the server's WebSocket listener.
+#[cfg(target_arch = "wasm32")]
+const DEFAULT_SERVER_ADDRESS: &str = "ws://127.0.0.1:8081";
+const SERVER_ADDRESS_FILE: &str = ".trapthemouse_server";
+/// How long a command the server refused stays mentioned on screen.
+const REFUSED_NOTICE: std::time::Duration = std::time::Duration::from_secs(5);
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+enum QueueRole {
+    Mouse,
+    Trapper,
+    Any,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct QueueEntry {
+    username: String,
+    role: QueueRole,
+    board: BoardConfig,
+    queued_at: u64,
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
-enum GameState {
-    Waiting,
+enum PresenceStatus {
+    Idle,
+    InLobby,
     InGame,
-    GameOver,
+    Spectating,
 }
 
-#[derive(Serialize, Deserialize, Clone, PartialEq)]
-enum Turn {
-    MousePlayer,
-    TrapperPlayer,
+#[derive(Serialize, Deserialize, Clone)]
+struct Presence {
+    username: String,
+    status: PresenceStatus,
 }
-#[derive(Serialize, Deserialize, Clone, PartialEq)]
 
-enum RoomType {
-    SinglePlayer,
-    MultiPlayer,
+#[derive(Serialize, Deserialize, Clone)]
+struct LeaderboardEntry {
+    username: String,
+    rating: f64,
+    games: u32,
 }
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+enum GameEnding {
+    Finished,
+    Forfeit(String),
+    Disconnect(String),
+}
+
 #[derive(Serialize, Deserialize, Clone)]
+struct PlayerStats {
+    username: String,
+    games_played: u32,
+    mouse_wins: u32,
+    mouse_losses: u32,
+    trapper_wins: u32,
+    trapper_losses: u32,
+    average_moves_to_trap: Option<f64>,
+    average_escape_length: Option<f64>,
+    forfeits: u32,
+    disconnects: u32,
+}
 
-enum Difficulty {
-    Easy,
-    Medium,
-    Hard,
+#[derive(Serialize, Deserialize, Clone)]
+struct MatchSummary {
+    match_id: u32,
+    role: Turn,
+    opponent: String,
+    won: Option<bool>,
+    ending: GameEnding,
+    rated: bool,
+    moves: usize,
+    ended_at: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
-struct Room {
-    room_id: u32,
+struct MatchRecord {
+    match_id: u32,
     room_name: String,
-    mouse_player: Option<String>,
-    trapper_player: Option<String>,
-    game_state: GameState,
-    mouse_position: (u32, u32),
-    walls: Vec<(u32, u32)>,
-    turn: Turn,
+    mouse_player: String,
+    trapper_player: String,
     winner: Option<Turn>,
-    room_type: RoomType,
-    game_difficulty: Option<Difficulty>,
-    mouse_player_exited: bool,
-    trapper_player_exited: bool,
+    ending: GameEnding,
+    rated: bool,
+    board: BoardConfig,
+    started_at: u64,
+    ended_at: u64,
+    starting_walls: Vec<(u32, u32)>,
+    moves: Vec<GameMove>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 struct Server {
     rooms: Vec<Room>,
+    #[serde(default)]
+    lobby_chat: Vec<ChatMessage>,
+    #[serde(default)]
+    presence: Vec<Presence>,
+    #[serde(default)]
+    queue: Vec<QueueEntry>,
+    #[serde(default)]
+    announcement: Option<Announcement>,
+    /// Seconds left while the server counts down to a shutdown.
+    #[serde(default)]
+    shutdown_in: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Announcement {
+    text: String,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn server_address_file() -> std::path::PathBuf {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(std::path::PathBuf::from)
+        .unwrap_or_default()
+        .join(SERVER_ADDRESS_FILE)
 }
 
+/// The server address on the first line, the trusted CA file for tls://
+/// servers on the second.
+fn parse_server_settings(contents: Option<String>) -> (String, String) {
+    let contents = contents.unwrap_or_default();
+    let mut lines = contents.lines().map(str::trim);
+    let address = lines
+        .next()
+        .filter(|address| !address.is_empty())
+        .unwrap_or(DEFAULT_SERVER_ADDRESS)
+        .to_string();
+    let trusted_ca = lines.next().unwrap_or("").to_string();
+    (address, trusted_ca)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn load_server_settings() -> (String, String) {
+    parse_server_settings(std::fs::read_to_string(server_address_file()).ok())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn save_server_settings(address: &str, trusted_ca: &str) {
+    let contents = format!("{}\n{}\n", address, trusted_ca.trim());
+    if let Err(e) = std::fs::write(server_address_file(), contents) {
+        warn!(error = %e, "could not remember the server address");
+    }
+}
+
+/// In the browser the address is kept in local storage instead of a file.
+#[cfg(target_arch = "wasm32")]
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+#[cfg(target_arch = "wasm32")]
+fn load_server_settings() -> (String, String) {
+    parse_server_settings(
+        local_storage().and_then(|storage| storage.get_item(SERVER_ADDRESS_FILE).ok()?),
+    )
+}
+
+#[cfg(target_arch = "wasm32")]
+fn save_server_settings(address: &str, trusted_ca: &str) {
+    if let Some(storage) = local_storage() {
+        let contents = format!("{}\n{}\n", address, trusted_ca.trim());
+        if storage.set_item(SERVER_ADDRESS_FILE, &contents).is_err() {
+            warn!("could not remember the server address");
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
 fn main() -> eframe::Result<()> {
+    let (level, format) = log_settings();
+    logging::init(level, format);
     let options = eframe::NativeOptions::default();
     eframe::run_native(
         "Trap The Mouse",
@@ -60,6 +204,40 @@ fn main() -> eframe::Result<()> {
     )
 }
 
+/// Log level and format from TRAPTHEMOUSE_LOG and TRAPTHEMOUSE_LOG_FORMAT,
+/// info and text when unset or invalid. They are environment variables for
+/// the native client and local storage keys in the browser.
+fn log_settings() -> (LogLevel, LogFormat) {
+    #[cfg(not(target_arch = "wasm32"))]
+    let setting = |name: &str| std::env::var(name).ok();
+    #[cfg(target_arch = "wasm32")]
+    let setting = |name: &str| local_storage()?.get_item(name).ok()?;
+    let level = setting("TRAPTHEMOUSE_LOG")
+        .and_then(|level| LogLevel::parse(&level).ok())
+        .unwrap_or(LogLevel::Info);
+    let format = setting("TRAPTHEMOUSE_LOG_FORMAT")
+        .and_then(|format| LogFormat::parse(&format).ok())
+        .unwrap_or(LogFormat::Text);
+    (level, format)
+}
+
+/// The browser build draws into the canvas of index.html.
+#[cfg(target_arch = "wasm32")]
+fn main() {
+    let (level, format) = log_settings();
+    logging::init(level, format);
+    wasm_bindgen_futures::spawn_local(async {
+        eframe::WebRunner::new()
+            .start(
+                "trap_the_mouse",
+                eframe::WebOptions::default(),
+                Box::new(|_cc| Box::new(MyApp::default())),
+            )
+            .await
+            .expect("failed to start the client");
+    });
+}
+
 enum AppState {
     Login,
     Menu,
@@ -69,37 +247,128 @@ enum AppState {
     MenuSinglePlayer,
     InGameSinglePlayer,
     GameOver,
+    Spectating,
+    Matchmaking,
+    Leaderboard,
+    Profile,
+    Replay,
 }
+
+/// Runs on the UI thread once the answer to a request arrives.
+type ReplyHandler = Box<dyn FnOnce(&mut MyApp, Result<String, String>)>;
+
 struct MyApp {
-    stream: Option<TcpStream>,
+    connection: Option<Connection>,
+    connection_state: ConnectionState,
+    server_hello: Option<Hello>,
+    pending_requests: Vec<(Receiver<Result<String, String>>, ReplyHandler)>,
+    server_address: String,
+    trusted_ca: String,
     username: String,
+    password: String,
+    login_message: String,
     app_state: AppState,
     new_room_name: String,
-    last_update_time: std::time::Instant,
+    new_room_private: bool,
+    new_room_use_password: bool,
+    new_room_password: String,
+    created_invite: Option<(String, String)>,
+    join_code: String,
+    queue_role: QueueRole,
+    queue_walls: u32,
+    queued_since: Option<Instant>,
+    matchmaking_notice: String,
+    /// Why the server last refused a command, and when.
+    refused: Option<(String, Instant)>,
+    leaderboard_role: Turn,
+    leaderboard: Result<Vec<LeaderboardEntry>, String>,
+    profile: Result<(PlayerStats, Vec<MatchSummary>), String>,
+    replay: Option<(MatchRecord, usize)>,
     update_interval: std::time::Duration,
     server_data: Option<Server>,
     current_room: Option<u32>,
     current_role: Option<Turn>,
+    chat_input: String,
+    lobby_chat_input: String,
 }
 
 impl Default for MyApp {
     fn default() -> Self {
+        let (server_address, trusted_ca) = load_server_settings();
         Self {
-            stream: TcpStream::connect("127.0.0.1:8080").ok(),
+            connection: None,
+            connection_state: ConnectionState::Connecting,
+            server_hello: None,
+            pending_requests: Vec::new(),
+            server_address,
+            trusted_ca,
             username: String::new(),
+            password: String::new(),
+            login_message: String::new(),
             app_state: AppState::Login,
             new_room_name: String::new(),
-            last_update_time: std::time::Instant::now(),
+            new_room_private: false,
+            new_room_use_password: true,
+            new_room_password: String::new(),
+            created_invite: None,
+            join_code: String::new(),
+            queue_role: QueueRole::Any,
+            queue_walls: 6,
+            queued_since: None,
+            matchmaking_notice: String::new(),
+            refused: None,
+            leaderboard_role: Turn::MousePlayer,
+            leaderboard: Ok(Vec::new()),
+            profile: Err(String::new()),
+            replay: None,
             update_interval: std::time::Duration::from_millis(10),
             server_data: None,
             current_room: None,
             current_role: None,
+            chat_input: String::new(),
+            lobby_chat_input: String::new(),
         }
     }
 }
 
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.get_updates();
+        if !matches!(self.app_state, AppState::Login) {
+            egui::TopBottomPanel::bottom("connection").show(ctx, |ui| {
+                ui.label(self.connection_status());
+                if let Some((reason, since)) = &self.refused {
+                    match REFUSED_NOTICE.checked_sub(since.elapsed()) {
+                        Some(left) => {
+                            ui.colored_label(Color32::LIGHT_RED, format!("Refused: {}", reason));
+                            ctx.request_repaint_after(left);
+                        }
+                        None => self.refused = None,
+                    }
+                }
+            });
+            let announcement = self
+                .server_data
+                .as_ref()
+                .and_then(|server| server.announcement.as_ref());
+            if let Some(announcement) = announcement {
+                egui::TopBottomPanel::top("announcement").show(ctx, |ui| {
+                    ui.colored_label(egui::Color32::YELLOW, &announcement.text);
+                });
+            }
+            let shutdown_in = self
+                .server_data
+                .as_ref()
+                .and_then(|server| server.shutdown_in);
+            if let Some(seconds) = shutdown_in {
+                egui::TopBottomPanel::top("shutdown").show(ctx, |ui| {
+                    ui.colored_label(
+                        Color32::LIGHT_RED,
+                        format!("The server is shutting down in {} seconds.", seconds),
+                    );
+                });
+            }
+        }
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.vertical_centered(|ui| match self.app_state {
                 AppState::Login => self.render_login(ui),
@@ -110,13 +379,13 @@ impl eframe::App for MyApp {
                 AppState::MenuSinglePlayer => self.render_menu_single_player(ui),
                 AppState::GameOver => self.render_game_over(ui),
                 AppState::InGameSinglePlayer => self.render_game_single_player(ui),
+                AppState::Spectating => self.render_spectator(ui),
+                AppState::Matchmaking => self.render_matchmaking(ui),
+                AppState::Leaderboard => self.render_leaderboard(ui),
+                AppState::Profile => self.render_profile(ui),
+                AppState::Replay => self.render_replay(ui),
             });
-            if self.stream.is_none()
-            {
-                exit(1);
-            }
         });
-        self.get_updates();
     }
 }
 
@@ -147,52 +416,123 @@ impl MyApp {
         neighbors.into_iter().all(|pos| traps.contains(&pos))
     }
 
-    fn send_command(&mut self, message: &str) {
-        if let Some(ref mut stream) = self.stream {
-            if let Err(e) = stream.write_all(message.as_bytes()) {
-                println!("ERR:mesaj spre server {}", e);
+    /// Connects to the address typed on the login screen, reusing the open
+    /// connection if the address and trusted CA did not change.
+    fn connect(&mut self, ctx: &egui::Context) {
+        let address = self.server_address.trim().to_string();
+        let trusted_ca = self.trusted_ca.trim().to_string();
+        let reusable = matches!(
+            (&self.connection, &self.connection_state),
+            (Some(connection), state)
+                if connection.address == address
+                    && connection.trusted_ca == trusted_ca
+                    && !matches!(state, ConnectionState::Failed(_))
+        );
+        if reusable {
+            return;
+        }
+        let ctx = ctx.clone();
+        self.connection = Some(Connection::open(
+            address,
+            trusted_ca,
+            self.update_interval,
+            Arc::new(move || ctx.request_repaint()),
+        ));
+        self.connection_state = ConnectionState::Connecting;
+        self.server_data = None;
+    }
+
+    fn connection_status(&self) -> String {
+        let address = self
+            .connection
+            .as_ref()
+            .map(|connection| connection.address.as_str())
+            .unwrap_or("");
+        match &self.connection_state {
+            ConnectionState::Connecting => format!("Connecting to {}...", address),
+            ConnectionState::Connected => format!("Connected to {}", address),
+            ConnectionState::Reconnecting(attempt) => {
+                format!("Connection lost, reconnecting to {} (attempt {})...", address, attempt)
             }
-        } else {
-            println!("No connection");
+            ConnectionState::Failed(e) => e.clone(),
         }
     }
 
-    fn get_updates(&mut self) {
-        if self.last_update_time.elapsed() >= self.update_interval {
-            if let Some(ref mut stream) = self.stream {
-                if let Err(e) = stream.write_all(b"get_update") {
-                    println!("Server stopped the connection {}", e);
-                    self.stream = None;
-                    exit(1);
-                }
-
-                let mut buffer = [0; 2048];
-                match stream.read(&mut buffer) {
-                    Ok(0) => {
-                        println!("Server stopped the stream");
-                        self.stream = None;
-                    }
-                    Ok(n) => {
-                        let message = String::from_utf8_lossy(&buffer[..n]);
+    /// Whether the server listed `capability` in its hello.
+    fn server_has(&self, capability: &str) -> bool {
+        self.server_hello
+            .as_ref()
+            .is_some_and(|hello| hello.has(capability))
+    }
 
-                        match serde_json::from_str::<Server>(&message) {
-                            Ok(server_data) => {
-                                self.server_data = Some(server_data.clone());
-                            }
-                            Err(e) => {
-                                println!("ERR:serialization {}", e);
-                            }
+    fn send_command(&mut self, message: &str) {
+        debug!(command = message.trim(), "sending");
+        match &self.connection {
+            Some(connection) => connection.send(message),
+            None => warn!("no connection for a command"),
+        }
+    }
+
+    /// Takes in what the connection received: connection state changes,
+    /// server updates and answers to requests.
+    fn get_updates(&mut self) {
+        if let Some(connection) = &self.connection {
+            let mut update = None;
+            while let Ok(event) = connection.events.try_recv() {
+                match event {
+                    Event::State(state) => {
+                        if state == ConnectionState::Connected {
+                            save_server_settings(&connection.address, &connection.trusted_ca);
                         }
+                        // Nothing works without the connection, so go back
+                        // to where the user can connect again.
+                        if let ConnectionState::Failed(e) = &state {
+                            self.login_message = e.clone();
+                            self.app_state = AppState::Login;
+                            self.current_room = None;
+                            self.current_role = None;
+                            self.queued_since = None;
+                            self.server_data = None;
+                        }
+                        self.connection_state = state;
                     }
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
-                    Err(e) => {
-                        println!("ERR: response  {}", e);
-                        self.stream = None;
-                    }
+                    Event::ServerHello(hello) => self.server_hello = Some(hello),
+                    Event::Update(message) => update = Some(message),
+                    Event::Refused(reason) => self.refused = Some((reason, Instant::now())),
+                }
+            }
+            if let Some(message) = update {
+                match serde_json::from_str::<Server>(&message) {
+                    Ok(server_data) => self.server_data = Some(server_data),
+                    Err(e) => warn!(error = %e, "could not read the server update"),
+                }
+            }
+        }
+
+        for (answer, on_reply) in std::mem::take(&mut self.pending_requests) {
+            match answer.try_recv() {
+                Ok(result) => on_reply(self, result),
+                Err(TryRecvError::Empty) => self.pending_requests.push((answer, on_reply)),
+                Err(TryRecvError::Disconnected) => {
+                    on_reply(self, Err("no answer from the server".to_string()))
                 }
             }
+        }
+    }
 
-            self.last_update_time = std::time::Instant::now();
+    /// Sends a command that the server answers with a single OK:/ERR: line
+    /// and hands the answer to `on_reply` when it arrives.
+    fn request(
+        &mut self,
+        message: &str,
+        on_reply: impl FnOnce(&mut MyApp, Result<String, String>) + 'static,
+    ) {
+        match &self.connection {
+            Some(connection) => {
+                let answer = connection.request(message);
+                self.pending_requests.push((answer, Box::new(on_reply)));
+            }
+            None => on_reply(self, Err("not connected to the server".to_string())),
         }
     }
 
@@ -201,21 +541,73 @@ impl MyApp {
         ui.heading("Trap The Mouse!");
         ui.add_space(40.0);
 
+        ui.label("Server:");
+        ui.add_space(5.0);
+
+        ui.add(egui::TextEdit::singleline(&mut self.server_address));
+        ui.add_space(10.0);
+
+        if cfg!(not(target_arch = "wasm32")) && self.server_address.trim().starts_with("tls://") {
+            ui.label("Trusted CA (PEM file, empty for the usual web roots):");
+            ui.add_space(5.0);
+
+            ui.add(egui::TextEdit::singleline(&mut self.trusted_ca));
+            ui.add_space(10.0);
+        }
+
         ui.label("Username:");
         ui.add_space(5.0);
 
         ui.add(egui::TextEdit::singleline(&mut self.username));
+        ui.add_space(10.0);
+
+        ui.label("Password:");
+        ui.add_space(5.0);
+
+        ui.add(egui::TextEdit::singleline(&mut self.password).password(true));
         ui.add_space(30.0);
 
         if ui.button("Play").clicked() {
-            if self.username.is_empty() {
-                ui.label("Enter username: ");
+            if self.username.is_empty() || self.password.is_empty() {
+                self.login_message = "Enter your username and password.".to_string();
             } else {
-                let command = format!("login {} ", self.username);
-                self.send_command(&command);
-                self.app_state = AppState::Menu;
+                self.connect(ui.ctx());
+                self.login_message = "Logging in...".to_string();
+                let command = format!("login {} {} ", self.username, self.password);
+                self.request(&command, |app, result| match result {
+                    Ok(_) => {
+                        app.password.clear();
+                        app.login_message.clear();
+                        app.app_state = AppState::Menu;
+                    }
+                    Err(e) => app.login_message = e,
+                });
             }
         }
+        ui.add_space(10.0);
+
+        if ui.button("Register").clicked() {
+            if self.username.is_empty() || self.password.is_empty() {
+                self.login_message = "Pick a username and password.".to_string();
+            } else if self.password.contains(char::is_whitespace) {
+                self.login_message = "The password cannot contain spaces.".to_string();
+            } else {
+                self.connect(ui.ctx());
+                self.login_message = "Creating the account...".to_string();
+                let command = format!("register {} {} ", self.username, self.password);
+                self.request(&command, |app, result| {
+                    app.login_message = match result {
+                        Ok(_) => "Account created, press Play to log in.".to_string(),
+                        Err(e) => e,
+                    };
+                });
+            }
+        }
+
+        if !self.login_message.is_empty() {
+            ui.add_space(20.0);
+            ui.label(&self.login_message);
+        }
     }
 
     fn render_menu(&mut self, ui: &mut egui::Ui) {
@@ -229,12 +621,45 @@ impl MyApp {
             ui.heading("Menu");
             ui.add_space(30.0);
 
+            // A game still holding our seat, e.g. after the server restarted.
+            let single_player_name = format!("!{}", self.username);
+            let unfinished = server_data.rooms.iter().find(|room| {
+                room.game_state == GameState::InGame
+                    && (room.mouse_player.as_ref() == Some(&self.username)
+                        || room.trapper_player.as_ref() == Some(&self.username)
+                        || room.room_name == single_player_name)
+            });
+            if let Some(room) = unfinished {
+                if ui.button(format!("Resume game in {}", room.room_name)).clicked() {
+                    self.current_room = Some(room.room_id);
+                    if room.room_type == RoomType::SinglePlayer {
+                        self.current_role = Some(Turn::TrapperPlayer);
+                        self.app_state = AppState::InGameSinglePlayer;
+                    } else {
+                        self.current_role = if room.mouse_player.as_ref() == Some(&self.username) {
+                            Some(Turn::MousePlayer)
+                        } else {
+                            Some(Turn::TrapperPlayer)
+                        };
+                        self.app_state = AppState::InGame;
+                    }
+                }
+                ui.add_space(20.0);
+            }
+
             if ui.button("SinglePlayer").clicked() {
-                let room_id = server_data.rooms.len() + 1;
-                let command = format!("create_single_room {} ", self.username);
                 self.current_role = Some(Turn::TrapperPlayer);
-                self.current_room = Some(room_id as u32);
-                self.send_command(&command);
+                self.current_room = None;
+                if self.server_has("replies") {
+                    // The server answers with the id of the new room.
+                    self.request("create_single_room ", |app, result| match result {
+                        Ok(room_id) => app.current_room = room_id.parse().ok(),
+                        Err(e) => app.refused = Some((e, Instant::now())),
+                    });
+                } else {
+                    let command = format!("create_single_room {} ", self.username);
+                    self.send_command(&command);
+                }
 
                 self.app_state = AppState::MenuSinglePlayer;
             }
@@ -243,9 +668,32 @@ impl MyApp {
             if ui.button("MultiPlayer").clicked() {
                 self.app_state = AppState::Rooms;
             }
-            ui.add_space(30.0);
+            ui.add_space(20.0);
+
+            if ui.button("Quick Match").clicked() {
+                self.matchmaking_notice.clear();
+                self.app_state = AppState::Matchmaking;
+            }
+            ui.add_space(20.0);
+
+            if self.server_has("ratings") {
+                if ui.button("Leaderboard").clicked() {
+                    self.refresh_leaderboard();
+                    self.app_state = AppState::Leaderboard;
+                }
+                ui.add_space(20.0);
+            }
+
+            if self.server_has("history") {
+                if ui.button("Profile").clicked() {
+                    self.refresh_profile();
+                    self.app_state = AppState::Profile;
+                }
+                ui.add_space(30.0);
+            }
 
             if ui.button("Back").clicked() {
+                self.send_command("logout ");
                 self.username.clear();
                 self.app_state = AppState::Login;
             }
@@ -265,10 +713,44 @@ impl MyApp {
             ui.label("Room Name");
             ui.add(egui::TextEdit::singleline(&mut self.new_room_name));
 
+            ui.checkbox(&mut self.new_room_private, "Private");
+            if self.new_room_private {
+                ui.horizontal(|ui| {
+                    ui.add_space(250.0);
+                    ui.radio_value(&mut self.new_room_use_password, true, "Password");
+                    ui.radio_value(&mut self.new_room_use_password, false, "Invite code");
+                });
+                if self.new_room_use_password {
+                    ui.label("Password (no spaces)");
+                    ui.add(egui::TextEdit::singleline(&mut self.new_room_password).password(true));
+                }
+            }
+
             if ui.button("Create Room").clicked() {
                 if self.new_room_name.is_empty()
                 {
-                    println!("insert a room name");
+                    debug!("create_room without a room name");
+                }
+                else if self.new_room_private
+                    && self.new_room_use_password
+                    && self.new_room_password.trim().is_empty()
+                {
+                    debug!("private room without a password");
+                }
+                else if self.new_room_private {
+                    let code = if self.new_room_use_password {
+                        self.new_room_password.split_whitespace().collect::<String>()
+                    } else {
+                        MyApp::generate_invite_code()
+                    };
+                    let command = format!("create_room {} code {} ", self.new_room_name, code);
+                    self.send_command(&command);
+                    if !self.new_room_use_password {
+                        self.created_invite = Some((self.new_room_name.clone(), code.clone()));
+                    }
+                    self.join_code = code;
+                    self.new_room_name.clear();
+                    self.new_room_password.clear();
                 }
                 else {
                     let command = format!("create_room {} ", self.new_room_name);
@@ -277,64 +759,149 @@ impl MyApp {
                     
                 }
             }
+
+            if let Some((room_name, code)) = self.created_invite.clone() {
+                ui.horizontal(|ui| {
+                    ui.add_space(250.0);
+                    ui.label(format!("Invite code for {}: {}", room_name, code));
+                    if ui.button("Copy").clicked() {
+                        ui.output_mut(|o| o.copied_text = code.clone());
+                    }
+                });
+            }
         });
 
         ui.heading("Rooms");
+        ui.horizontal(|ui| {
+            ui.add_space(250.0);
+            ui.label("Code for private rooms:");
+            ui.add(egui::TextEdit::singleline(&mut self.join_code).desired_width(120.0));
+        });
         ui.add_space(30.0);
 
-        let rooms = if let Some(server_data) = &self.server_data {
-            server_data.rooms.clone()
+        let (rooms, presence, lobby_chat) = if let Some(server_data) = &self.server_data {
+            (
+                server_data.rooms.clone(),
+                server_data.presence.clone(),
+                server_data.lobby_chat.clone(),
+            )
         } else {
-            vec![]
+            (vec![], vec![], vec![])
         };
 
         ui.horizontal(|ui| {
-            ui.add_space(250.0);
+            ui.add_space(150.0);
+
+            ui.vertical(|ui| {
+                ui.set_width(420.0);
+                egui::ScrollArea::vertical()
+                    .id_source("room_list")
+                    .max_height(300.0)
+                    .show(ui, |ui| {
+                        for room in &rooms {
+                            if room.room_type == RoomType::MultiPlayer {
+                                ui.horizontal(|ui| {
+                                    ui.label(&room.room_name);
 
-            ui.vertical_centered(|ui| {
-                egui::ScrollArea::vertical().show(ui, |ui| {
-                    for room in &rooms {
-                        if room.room_type == RoomType::MultiPlayer {
-                            ui.horizontal(|ui| {
-                                ui.label(&room.room_name);
+                                    let room_id = room.room_id;
 
-                                let room_id = room.room_id;
+                                    if room.rated {
+                                        ui.label("[rated]");
+                                    }
+                                    if room.locked {
+                                        ui.label("[locked]");
+                                        if self.join_code.trim().is_empty() {
+                                            ui.label("Enter the room code to join.");
+                                            return;
+                                        }
+                                    }
+                                    let code = if room.locked { self.join_code.trim() } else { "" };
+                                    let code = code.to_string();
 
-                                if room.mouse_player.is_none()
-                                    && ui.button("Join as Mouse").clicked()
-                                {
-                                    let command = format!(
-                                        "join_room {} mouse {} ",
-                                        room_id,
-                                        self.username.clone()
-                                    );
-                                    println!("{}", command);
+                                    let waiting = room.game_state == GameState::Waiting;
 
-                                    self.send_command(&command);
-                                    self.app_state = AppState::Lobby;
-                                    self.current_room = Some(room_id);
-                                    self.current_role = Some(Turn::MousePlayer);
-                                }
-                                if room.trapper_player.is_none() && ui.button("Join as Trapper").clicked() {
+                                    if waiting
+                                        && room.mouse_player.is_none()
+                                        && ui.button("Join as Mouse").clicked()
+                                    {
                                         let command = format!(
-                                            "join_room {} trapper {} ",
+                                            "join_room {} mouse {} {} ",
                                             room_id,
-                                            self.username.clone()
+                                            self.username.clone(),
+                                            code
                                         );
-                                        println!("{}", command);
                                         self.send_command(&command);
                                         self.app_state = AppState::Lobby;
                                         self.current_room = Some(room_id);
-                                        self.current_role = Some(Turn::TrapperPlayer);
+                                        self.current_role = Some(Turn::MousePlayer);
                                     }
-                                if room.trapper_player.is_some() && room.mouse_player.is_some() {
-                                    ui.label("Room is full.");
-                                }
-                            });
+                                    if waiting && room.trapper_player.is_none() && ui.button("Join as Trapper").clicked() {
+                                            let command = format!(
+                                                "join_room {} trapper {} {} ",
+                                                room_id,
+                                                self.username.clone(),
+                                                code
+                                            );
+                                            self.send_command(&command);
+                                            self.app_state = AppState::Lobby;
+                                            self.current_room = Some(room_id);
+                                            self.current_role = Some(Turn::TrapperPlayer);
+                                        }
+                                    if !waiting {
+                                        ui.label("Game in progress.");
+                                    } else if room.trapper_player.is_some() && room.mouse_player.is_some() {
+                                        ui.label("Room is full.");
+                                    }
+                                    if ui.button("Spectate").clicked() {
+                                        let command = format!(
+                                            "join_room {} spectator {} {} ",
+                                            room_id,
+                                            self.username.clone(),
+                                            code
+                                        );
+                                        self.send_command(&command);
+                                        self.app_state = AppState::Spectating;
+                                        self.current_room = Some(room_id);
+                                        self.current_role = None;
+                                    }
+                                });
+                            }
                         }
-                    }
-                });
+                    });
+                ui.add_space(10.0);
+            });
+
+            ui.add_space(30.0);
+
+            ui.vertical(|ui| {
+                ui.set_width(300.0);
+                ui.label(format!("Online ({})", presence.len()));
+                egui::ScrollArea::vertical()
+                    .id_source("presence_list")
+                    .max_height(120.0)
+                    .show(ui, |ui| {
+                        for player in &presence {
+                            let status = match player.status {
+                                PresenceStatus::Idle => "idle",
+                                PresenceStatus::InLobby => "in lobby",
+                                PresenceStatus::InGame => "in game",
+                                PresenceStatus::Spectating => "spectating",
+                            };
+                            ui.label(format!("{} - {}", player.username, status));
+                        }
+                    });
                 ui.add_space(10.0);
+
+                if let Some(text) = MyApp::chat_box(
+                    ui,
+                    "lobby_chat",
+                    "Lobby Chat",
+                    &lobby_chat,
+                    &mut self.lobby_chat_input,
+                ) {
+                    let command = format!("lobby_chat {}", text);
+                    self.send_command(&command);
+                }
             });
         });
 
@@ -352,16 +919,6 @@ impl MyApp {
             {
                 let current_role = self.current_role.clone();
 
-                let (x, y) = room.mouse_position;
-                let is_on_edge = x == 0 || x == 10 || y == 0 || y == 10;
-
-                if is_on_edge {
-                    let command = format!("game_over {} mouse ", room.room_id);
-                    self.send_command(&command)
-                } else if MyApp::is_surrounded(room.mouse_position, &room.walls) {
-                    let command = format!("game_over {} trapper ", room.room_id);
-                    self.send_command(&command)
-                }
                 if room.game_state == GameState::GameOver {
                     self.app_state = AppState::GameOver;
                 } else {
@@ -395,6 +952,17 @@ impl MyApp {
                     } else {
                         ui.label("OPPONENT");
                     }
+                    if !room.spectators.is_empty() {
+                        ui.label(format!("Spectators: {}", room.spectators.join(", ")));
+                    }
+                    if let Some(started_at) = room.started_at {
+                        let now = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map(|elapsed| elapsed.as_secs())
+                            .unwrap_or(started_at);
+                        let elapsed = now.saturating_sub(started_at);
+                        ui.label(format!("Time: {:02}:{:02}", elapsed / 60, elapsed % 60));
+                    }
 
                     ui.add_space(20.0);
                     ui.horizontal(|ui| {
@@ -534,20 +1102,17 @@ impl MyApp {
                     ui.add_space(20.0);
 
                     if let Some(command) = command_to_send {
-                        println!("Sending command: {}", command);
                         self.send_command(&command);
                     }
 
+                    self.render_chat(ui, room);
+                    ui.add_space(20.0);
+
                     if ui.button("Back to Menu").clicked() {
-                        if self.current_role == Some(Turn::MousePlayer) {
-                            let command = format!("after_exit_room {} mouse ", room.room_id);
-                            self.send_command(&command);
-                        } else if self.current_role == Some(Turn::TrapperPlayer) {
-                            let command = format!("after_exit_room {} trapper ", room.room_id);
+                        if self.current_role.is_some() {
+                            let command = format!("after_exit_room {} ", room.room_id);
                             self.send_command(&command);
                         }
-                        let command = format!("game_over {} none ", room.room_id);
-                        self.send_command(&command);
                         self.app_state = AppState::Menu;
                     }
                 }
@@ -600,14 +1165,8 @@ impl MyApp {
                 let (x, y) = room.mouse_position;
                 let is_on_edge = x == 0 || x == 10 || y == 0 || y == 10;
 
-                if is_on_edge {
-                    let command = format!("game_over {} mouse ", room.room_id);
-                    self.send_command(&command);
-                    self.app_state = AppState::GameOver;
-                } else if MyApp::is_surrounded(room.mouse_position, &room.walls) {
-                    let command = format!("game_over {} trapper ", room.room_id);
+                if is_on_edge || MyApp::is_surrounded(room.mouse_position, &room.walls) {
                     self.app_state = AppState::GameOver;
-                    self.send_command(&command);
                 } else {
                     if room.game_state == GameState::GameOver {
                         self.app_state = AppState::GameOver;
@@ -709,7 +1268,6 @@ impl MyApp {
                     ui.add_space(20.0);
 
                     if let Some(command) = command_to_send {
-                        println!("Sending command: {}", command);
                         self.send_command(&command);
                     }
 
@@ -737,6 +1295,16 @@ impl MyApp {
                 .iter()
                 .find(|room| Some(room.room_id) == self.current_room)
             {
+                if room.room_type == RoomType::MultiPlayer && room.game_state == GameState::InGame {
+                    self.current_role = if room.mouse_player.as_ref() == Some(&self.username) {
+                        Some(Turn::MousePlayer)
+                    } else {
+                        Some(Turn::TrapperPlayer)
+                    };
+                    self.app_state = AppState::InGame;
+                    return;
+                }
+
                 ui.add_space(250.0);
 
                 if self.current_role == Some(Turn::MousePlayer) {
@@ -752,19 +1320,49 @@ impl MyApp {
                     } else {
                         ui.heading("Your opponent got freaked out! You are really scary!");
                     }
+
+                if room.room_type == RoomType::MultiPlayer {
+                    ui.add_space(30.0);
+                    if !room.series_score.is_empty() {
+                        let score: Vec<String> = room
+                            .series_score
+                            .iter()
+                            .map(|(name, wins)| format!("{} {}", name, wins))
+                            .collect();
+                        ui.label(format!("Series: {}", score.join(" - ")));
+                    }
+
+                    let swap_note = if room.rematch_swap { " (swapping roles)" } else { "" };
+                    if room.mouse_player_exited || room.trapper_player_exited {
+                        ui.label("Your opponent left the room.");
+                    } else if room.rematch_votes.contains(&self.username) {
+                        ui.label(format!(
+                            "Waiting for your opponent to accept the rematch{}",
+                            swap_note
+                        ));
+                    } else {
+                        if !room.rematch_votes.is_empty() {
+                            ui.label(format!("Your opponent wants a rematch{}", swap_note));
+                        }
+                        if ui.button("Rematch").clicked() {
+                            let command = format!("rematch {} keep ", room.room_id);
+                            self.send_command(&command);
+                        }
+                        if ui.button("Rematch (swap roles)").clicked() {
+                            let command = format!("rematch {} swap ", room.room_id);
+                            self.send_command(&command);
+                        }
+                    }
+                }
+
                 ui.add_space(100.0);
                 if ui.button("Back to Menu").clicked() {
                     if room.room_type == RoomType::SinglePlayer {
                         let command = format!("delete_room {} ", room.room_id);
                         self.send_command(&command);
                         self.app_state = AppState::Menu;
-                    }
-                    if self.current_role == Some(Turn::MousePlayer) {
-                        let command = format!("after_exit_room {} mouse ", room.room_id);
-                        self.send_command(&command);
-                        self.app_state = AppState::Menu;
-                    } else if self.current_role == Some(Turn::TrapperPlayer) {
-                        let command = format!("after_exit_room {} trapper ", room.room_id);
+                    } else if self.current_role.is_some() {
+                        let command = format!("after_exit_room {} ", room.room_id);
                         self.send_command(&command);
                         self.app_state = AppState::Menu;
                     }
@@ -784,12 +1382,21 @@ impl MyApp {
 
         ui.add_space(30.0);
 
-        if let Some(server_data) = &self.server_data {
+        if let Some(server_data) = &self.server_data.clone() {
             if let Some(room) = server_data
                 .rooms
                 .iter()
                 .find(|room| Some(room.room_id) == self.current_room)
             {
+                let seated = room.mouse_player.as_ref() == Some(&self.username)
+                    || room.trapper_player.as_ref() == Some(&self.username);
+                if room.game_state == GameState::InGame && seated {
+                    self.app_state = AppState::InGame;
+                    return;
+                }
+
+                let ready_label = |ready: bool| if ready { " (ready)" } else { "" };
+
                 ui.heading(&room.room_name);
                 ui.horizontal(|ui| {
                     ui.add_space(250.0);
@@ -799,6 +1406,7 @@ impl MyApp {
                             .as_ref()
                             .unwrap_or(&"Waiting for the other player".to_string()),
                     );
+                    ui.label(ready_label(room.mouse_ready));
                 });
 
                 ui.horizontal(|ui| {
@@ -809,14 +1417,38 @@ impl MyApp {
                             .as_ref()
                             .unwrap_or(&"Waiting for the other player".to_string()),
                     );
+                    ui.label(ready_label(room.trapper_ready));
                 });
 
-                if room.trapper_player.is_some() && room.mouse_player.is_some() {
-                    self.app_state = AppState::InGame;
-                    // let command = format!("started_game {} ", room.room_id.clone());
-                    // self.send_command(&command);
+                if !room.spectators.is_empty() {
+                    ui.label(format!("Spectators: {}", room.spectators.join(", ")));
                 }
 
+                ui.add_space(20.0);
+                if !seated {
+                    ui.label("You could not take this seat: wrong room code, game already started or seat taken.");
+                } else {
+                    let ready = if self.current_role == Some(Turn::MousePlayer) {
+                        room.mouse_ready
+                    } else {
+                        room.trapper_ready
+                    };
+                    if ready {
+                        ui.label("Waiting for the other player to be ready");
+                        if ui.button("Not Ready").clicked() {
+                            let command = format!("ready {} no ", room.room_id);
+                            self.send_command(&command);
+                        }
+                    } else if ui.button("Ready").clicked() {
+                        let command = format!("ready {} yes ", room.room_id);
+                        self.send_command(&command);
+                    }
+                }
+
+                ui.add_space(20.0);
+                self.render_chat(ui, room);
+                ui.add_space(20.0);
+
                 if ui.button("Back").clicked() {
                     ui.add_space(250.0);
                     let command = format!("exit_room {} {} ", room.room_id.clone(), self.username);
@@ -827,4 +1459,514 @@ impl MyApp {
             }
         }
     }
+
+    fn render_board_read_only(
+        ui: &mut egui::Ui,
+        mouse_position: (u32, u32),
+        walls: &[(u32, u32)],
+    ) {
+        ui.horizontal(|ui| {
+            ui.add_space(250.0);
+            ui.vertical(|ui| {
+                for x in 0..11 {
+                    ui.horizontal(|ui| {
+                        if x % 2 == 1 {
+                            ui.add_space(15.0);
+                        }
+                        for y in 0..11 {
+                            let base_color = if (x, y) == mouse_position {
+                                Color32::from_gray(200)
+                            } else if walls.contains(&(x, y)) {
+                                Color32::from_rgb(255, 0, 0)
+                            } else {
+                                Color32::from_gray(100)
+                            };
+
+                            Frame::none()
+                                .fill(base_color)
+                                .rounding(egui::Rounding::same(5.0))
+                                .show(ui, |ui| {
+                                    ui.add_enabled(
+                                        false,
+                                        Button::new("")
+                                            .frame(false)
+                                            .min_size(egui::vec2(20.0, 20.0)),
+                                    );
+                                });
+                        }
+                    });
+                }
+            });
+        });
+    }
+
+    fn render_spectator(&mut self, ui: &mut egui::Ui) {
+        ui.add_space(40.0);
+        ui.heading("Trap The Mouse!");
+        ui.label(format!("Spectating as: {} ", self.username));
+        ui.add_space(20.0);
+
+        let room = self.server_data.as_ref().and_then(|server_data| {
+            server_data
+                .rooms
+                .iter()
+                .find(|room| Some(room.room_id) == self.current_room)
+                .cloned()
+        });
+
+        match room {
+            Some(room) => {
+                ui.heading(&room.room_name);
+                ui.label(format!(
+                    "Mouse Player: {}",
+                    room.mouse_player.as_deref().unwrap_or("Waiting for a player")
+                ));
+                ui.label(format!(
+                    "Trapper Player: {}",
+                    room.trapper_player.as_deref().unwrap_or("Waiting for a player")
+                ));
+                ui.label(format!("Spectators: {}", room.spectators.join(", ")));
+                ui.add_space(10.0);
+
+                match room.game_state {
+                    GameState::Waiting => {
+                        ui.label("Waiting for the game to start");
+                    }
+                    GameState::InGame => {
+                        if room.turn == Turn::MousePlayer {
+                            ui.label(">MOVING NOW< Mouse");
+                        } else {
+                            ui.label(">MOVING NOW< Trapper");
+                        }
+                    }
+                    GameState::GameOver => match room.winner {
+                        Some(Turn::MousePlayer) => {
+                            ui.label("Game over: the mouse escaped!");
+                        }
+                        Some(Turn::TrapperPlayer) => {
+                            ui.label("Game over: the mouse was trapped!");
+                        }
+                        None => {
+                            ui.label("Game over: a player left the game");
+                        }
+                    },
+                }
+
+                ui.add_space(20.0);
+                MyApp::render_board_read_only(ui, room.mouse_position, &room.walls);
+                ui.add_space(20.0);
+                self.render_chat(ui, &room);
+                ui.add_space(20.0);
+
+                if ui.button("Leave").clicked() {
+                    let command = format!("exit_room {} {} ", room.room_id, self.username);
+                    self.send_command(&command);
+                    self.current_room = None;
+                    self.app_state = AppState::Rooms;
+                }
+            }
+            None => {
+                ui.label("This room has been closed.");
+                ui.add_space(20.0);
+                if ui.button("Back to Rooms").clicked() {
+                    self.current_room = None;
+                    self.app_state = AppState::Rooms;
+                }
+            }
+        }
+    }
+
+    fn chat_box(
+        ui: &mut egui::Ui,
+        id: &str,
+        title: &str,
+        messages: &[ChatMessage],
+        input: &mut String,
+    ) -> Option<String> {
+        let mut submitted_text = None;
+        ui.group(|ui| {
+            ui.set_max_width(400.0);
+            ui.label(title);
+            egui::ScrollArea::vertical()
+                .id_source(id)
+                .max_height(120.0)
+                .stick_to_bottom(true)
+                .show(ui, |ui| {
+                    for message in messages {
+                        ui.label(format!("{}: {}", message.username, message.text));
+                    }
+                });
+
+            ui.horizontal(|ui| {
+                let response = ui.add(
+                    egui::TextEdit::singleline(input)
+                        .char_limit(200)
+                        .hint_text("Say something"),
+                );
+                let submitted =
+                    response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                if (ui.button("Send").clicked() || submitted) && !input.trim().is_empty() {
+                    submitted_text = Some(input.trim().to_string());
+                    input.clear();
+                }
+            });
+        });
+        submitted_text
+    }
+
+    fn render_chat(&mut self, ui: &mut egui::Ui, room: &Room) {
+        if let Some(text) =
+            MyApp::chat_box(ui, "room_chat", "Chat", &room.chat, &mut self.chat_input)
+        {
+            let command = format!("chat {} {}", room.room_id, text);
+            self.send_command(&command);
+        }
+    }
+
+    fn generate_invite_code() -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+        let mut rng = rand::thread_rng();
+        (0..6)
+            .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+            .collect()
+    }
+
+    fn render_matchmaking(&mut self, ui: &mut egui::Ui) {
+        ui.add_space(80.0);
+        ui.heading("Trap The Mouse!");
+        ui.label(format!("Connected as: {} ", self.username));
+        ui.add_space(30.0);
+        ui.heading("Quick Match");
+        ui.add_space(30.0);
+
+        let server_data = match &self.server_data {
+            Some(server_data) => server_data.clone(),
+            None => return,
+        };
+
+        if self.queued_since.is_some() {
+            if let Some(room) = server_data.rooms.iter().find(|room| {
+                room.mouse_player.as_ref() == Some(&self.username)
+                    || room.trapper_player.as_ref() == Some(&self.username)
+            }) {
+                self.queued_since = None;
+                self.current_room = Some(room.room_id);
+                self.current_role = if room.mouse_player.as_ref() == Some(&self.username) {
+                    Some(Turn::MousePlayer)
+                } else {
+                    Some(Turn::TrapperPlayer)
+                };
+                self.app_state = if room.game_state == GameState::InGame {
+                    AppState::InGame
+                } else {
+                    AppState::Lobby
+                };
+                return;
+            }
+        }
+
+        let entry = server_data
+            .queue
+            .iter()
+            .find(|entry| entry.username == self.username);
+
+        match (self.queued_since, entry) {
+            (Some(_), Some(entry)) => {
+                let waited = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_secs())
+                    .unwrap_or(entry.queued_at)
+                    .saturating_sub(entry.queued_at);
+                ui.label(format!("Searching for an opponent... {}s", waited));
+                ui.label(format!("Players searching: {}", server_data.queue.len()));
+                ui.add_space(20.0);
+                if ui.button("Cancel").clicked() {
+                    self.send_command("leave_queue ");
+                    self.queued_since = None;
+                }
+            }
+            (Some(since), None) if since.elapsed() > std::time::Duration::from_secs(2) => {
+                self.queued_since = None;
+                self.matchmaking_notice = "No opponent found in time. Try again.".to_string();
+            }
+            (Some(_), None) => {
+                ui.label("Joining the queue...");
+            }
+            (None, _) => {
+                if !self.matchmaking_notice.is_empty() {
+                    ui.label(&self.matchmaking_notice);
+                    ui.add_space(10.0);
+                }
+                ui.label("Preferred role");
+                ui.horizontal(|ui| {
+                    ui.add_space(250.0);
+                    ui.radio_value(&mut self.queue_role, QueueRole::Mouse, "Mouse");
+                    ui.radio_value(&mut self.queue_role, QueueRole::Trapper, "Trapper");
+                    ui.radio_value(&mut self.queue_role, QueueRole::Any, "Any");
+                });
+                ui.add_space(10.0);
+                ui.add(egui::Slider::new(&mut self.queue_walls, 0..=20).text("starting walls"));
+                ui.add_space(20.0);
+
+                if ui.button("Find Match").clicked() {
+                    let role = match self.queue_role {
+                        QueueRole::Mouse => "mouse",
+                        QueueRole::Trapper => "trapper",
+                        QueueRole::Any => "any",
+                    };
+                    let command = format!("join_queue {} {} ", role, self.queue_walls);
+                    self.send_command(&command);
+                    self.matchmaking_notice.clear();
+                    self.queued_since = Some(Instant::now());
+                }
+                ui.add_space(20.0);
+                if ui.button("Back to Menu").clicked() {
+                    self.app_state = AppState::Menu;
+                }
+            }
+        }
+    }
+
+    fn refresh_leaderboard(&mut self) {
+        let role = match self.leaderboard_role {
+            Turn::MousePlayer => "mouse",
+            Turn::TrapperPlayer => "trapper",
+        };
+        self.request(&format!("leaderboard {} ", role), |app, result| {
+            app.leaderboard =
+                result.and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()));
+        });
+    }
+
+    fn render_leaderboard(&mut self, ui: &mut egui::Ui) {
+        ui.add_space(80.0);
+        ui.heading("Leaderboard");
+        ui.add_space(20.0);
+
+        let previous_role = self.leaderboard_role.clone();
+        ui.horizontal(|ui| {
+            ui.add_space(300.0);
+            ui.radio_value(&mut self.leaderboard_role, Turn::MousePlayer, "Mouse");
+            ui.radio_value(&mut self.leaderboard_role, Turn::TrapperPlayer, "Trapper");
+        });
+        if self.leaderboard_role != previous_role {
+            self.refresh_leaderboard();
+        }
+        ui.add_space(20.0);
+
+        match &self.leaderboard {
+            Ok(entries) if entries.is_empty() => {
+                ui.label("No rated games played yet.");
+            }
+            Ok(entries) => {
+                egui::Grid::new("leaderboard").striped(true).show(ui, |ui| {
+                    ui.label("#");
+                    ui.label("Player");
+                    ui.label("Rating");
+                    ui.label("Games");
+                    ui.end_row();
+                    for (rank, entry) in entries.iter().enumerate() {
+                        ui.label(format!("{}", rank + 1));
+                        if entry.username == self.username {
+                            ui.strong(&entry.username);
+                        } else {
+                            ui.label(&entry.username);
+                        }
+                        ui.label(format!("{:.0}", entry.rating));
+                        ui.label(format!("{}", entry.games));
+                        ui.end_row();
+                    }
+                });
+            }
+            Err(e) => {
+                ui.label(format!("Could not load the leaderboard: {}", e));
+            }
+        }
+        ui.add_space(20.0);
+
+        if ui.button("Refresh").clicked() {
+            self.refresh_leaderboard();
+        }
+        ui.add_space(10.0);
+        if ui.button("Back to Menu").clicked() {
+            self.app_state = AppState::Menu;
+        }
+    }
+
+    fn refresh_profile(&mut self) {
+        self.request("stats ", |app, result| {
+            let stats: PlayerStats =
+                match result.and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string())) {
+                    Ok(stats) => stats,
+                    Err(e) => {
+                        app.profile = Err(e);
+                        return;
+                    }
+                };
+            app.request("history ", |app, result| {
+                app.profile = result
+                    .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()))
+                    .map(|history| (stats, history));
+            });
+        });
+    }
+
+    fn render_profile(&mut self, ui: &mut egui::Ui) {
+        ui.add_space(60.0);
+        ui.heading(format!("Profile: {}", self.username));
+        ui.add_space(20.0);
+
+        let mut open_replay = None;
+        match &self.profile {
+            Ok((stats, history)) => {
+                let average = |value: Option<f64>| match value {
+                    Some(value) => format!("{:.1}", value),
+                    None => "-".to_string(),
+                };
+                ui.label(format!("Games played: {}", stats.games_played));
+                ui.label(format!(
+                    "As mouse: {} won, {} lost",
+                    stats.mouse_wins, stats.mouse_losses
+                ));
+                ui.label(format!(
+                    "As trapper: {} won, {} lost",
+                    stats.trapper_wins, stats.trapper_losses
+                ));
+                ui.label(format!(
+                    "Average moves to trap: {}",
+                    average(stats.average_moves_to_trap)
+                ));
+                ui.label(format!(
+                    "Average escape length: {}",
+                    average(stats.average_escape_length)
+                ));
+                ui.label(format!(
+                    "Forfeits: {}  Disconnects: {}",
+                    stats.forfeits, stats.disconnects
+                ));
+                ui.add_space(20.0);
+
+                ui.heading("Past games");
+                if history.is_empty() {
+                    ui.label("No games played yet.");
+                }
+                egui::ScrollArea::vertical()
+                    .max_height(300.0)
+                    .show(ui, |ui| {
+                        egui::Grid::new("history").striped(true).show(ui, |ui| {
+                            for game in history {
+                                let role = match game.role {
+                                    Turn::MousePlayer => "mouse",
+                                    Turn::TrapperPlayer => "trapper",
+                                };
+                                let result = match (game.won, &game.ending) {
+                                    (_, GameEnding::Forfeit(player)) if *player == self.username => {
+                                        "forfeited"
+                                    }
+                                    (_, GameEnding::Disconnect(player))
+                                        if *player == self.username =>
+                                    {
+                                        "disconnected"
+                                    }
+                                    (Some(true), _) => "won",
+                                    (Some(false), _) => "lost",
+                                    (None, _) => "no winner",
+                                };
+                                ui.label(format!("#{}", game.match_id));
+                                ui.label(format!("{} vs {}", role, game.opponent));
+                                ui.label(result);
+                                ui.label(if game.rated { "rated" } else { "friendly" });
+                                ui.label(format!("{} moves", game.moves));
+                                if ui.button("Replay").clicked() {
+                                    open_replay = Some(game.match_id);
+                                }
+                                ui.end_row();
+                            }
+                        });
+                    });
+            }
+            Err(e) if e.is_empty() => {}
+            Err(e) => {
+                ui.label(format!("Could not load the profile: {}", e));
+            }
+        }
+
+        if let Some(match_id) = open_replay {
+            self.request(&format!("replay {} ", match_id), |app, result| {
+                match result.and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string())) {
+                    Ok(record) => {
+                        app.replay = Some((record, 0));
+                        app.app_state = AppState::Replay;
+                    }
+                    Err(e) => app.profile = Err(e),
+                }
+            });
+        }
+        ui.add_space(20.0);
+
+        if ui.button("Refresh").clicked() {
+            self.refresh_profile();
+        }
+        ui.add_space(10.0);
+        if ui.button("Back to Menu").clicked() {
+            self.app_state = AppState::Menu;
+        }
+    }
+
+    fn render_replay(&mut self, ui: &mut egui::Ui) {
+        ui.add_space(40.0);
+        let Some((record, step)) = &mut self.replay else {
+            self.app_state = AppState::Profile;
+            return;
+        };
+
+        ui.heading(format!("Replay of game #{}", record.match_id));
+        ui.label(format!(
+            "Mouse: {}  Trapper: {}",
+            record.mouse_player, record.trapper_player
+        ));
+        ui.add_space(10.0);
+
+        let mut mouse_position = (5, 5);
+        let mut walls = record.starting_walls.clone();
+        for game_move in &record.moves[..*step] {
+            match *game_move {
+                GameMove::Mouse(x, y) => mouse_position = (x, y),
+                GameMove::Wall(x, y) => walls.push((x, y)),
+            }
+        }
+        MyApp::render_board_read_only(ui, mouse_position, &walls);
+        ui.add_space(10.0);
+
+        ui.label(format!("Move {} of {}", step, record.moves.len()));
+        ui.horizontal(|ui| {
+            ui.add_space(300.0);
+            if ui.button("<<").clicked() {
+                *step = 0;
+            }
+            if ui.button("<").clicked() && *step > 0 {
+                *step -= 1;
+            }
+            if ui.button(">").clicked() && *step < record.moves.len() {
+                *step += 1;
+            }
+            if ui.button(">>").clicked() {
+                *step = record.moves.len();
+            }
+        });
+        if *step == record.moves.len() {
+            let winner = match &record.winner {
+                Some(Turn::MousePlayer) => record.mouse_player.as_str(),
+                Some(Turn::TrapperPlayer) => record.trapper_player.as_str(),
+                None => "nobody",
+            };
+            ui.label(format!("Winner: {}", winner));
+        }
+        ui.add_space(20.0);
+
+        if ui.button("Back to Profile").clicked() {
+            self.replay = None;
+            self.app_state = AppState::Profile;
+        }
+    }
 }
@@ -0,0 +1,333 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::game::{BoardConfig, MAX_INITIAL_WALLS};
+use crate::logging::{LogFormat, LogLevel};
+
+pub const DEFAULT_CONFIG_PATH: &str = "server.toml";
+/// Overrides admin.token, so the token can stay out of the config file.
+pub const ADMIN_TOKEN_VARIABLE: &str = "TRAPTHEMOUSE_ADMIN_TOKEN";
+/// Enough for any command a client sends during normal play.
+const MIN_MESSAGE_BYTES: usize = 256;
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timers {
+    pub queue_timeout_secs: u64,
+    pub snapshot_interval_secs: u64,
+    /// Countdown shown to clients between SIGINT/SIGTERM and closing.
+    pub shutdown_grace_secs: u64,
+    /// How long a player whose connection dropped keeps their seats.
+    pub reconnect_grace_secs: u64,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self {
+            queue_timeout_secs: 120,
+            snapshot_interval_secs: 60,
+            shutdown_grace_secs: 10,
+            reconnect_grace_secs: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceConfig {
+    /// Snapshots and the command log; accounts and match history are always saved.
+    pub enabled: bool,
+    pub data_dir: PathBuf,
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            data_dir: PathBuf::from("."),
+        }
+    }
+}
+
+/// Protection against clients that flood the server. Setting a rate or a
+/// count to 0 turns that limit off.
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Longer commands are refused.
+    pub max_message_bytes: usize,
+    /// Per connection, not counting get_update.
+    pub commands_per_second: u32,
+    pub command_burst: u32,
+    /// get_update per connection; the client polls every 10 ms.
+    pub updates_per_second: u32,
+    /// Every connection of one user together, not counting get_update.
+    pub user_commands_per_second: u32,
+    /// Rooms one user (or one anonymous connection) can have open at once.
+    pub rooms_per_user: usize,
+    /// Connections that send nothing for this long are closed.
+    pub idle_timeout_secs: u64,
+    /// Violations within a minute before the connection is closed.
+    pub max_violations: usize,
+    /// Failed logins from one address within a minute before its logins
+    /// are refused for the rest of that minute.
+    pub failed_logins_per_minute: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_message_bytes: 1024,
+            commands_per_second: 20,
+            command_burst: 40,
+            updates_per_second: 200,
+            user_commands_per_second: 40,
+            rooms_per_user: 3,
+            idle_timeout_secs: 300,
+            max_violations: 10,
+            failed_logins_per_minute: 5,
+        }
+    }
+}
+
+/// PEM files for the certificate chain and its private key.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// The operators' console. Every connection has to start with `auth <token>`.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    pub bind: String,
+    /// May be left out when $TRAPTHEMOUSE_ADMIN_TOKEN is set.
+    #[serde(default)]
+    pub token: String,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: String,
+    /// Where WebSocket clients connect; no WebSocket listener when unset.
+    pub websocket_bind: Option<String>,
+    pub max_rooms: usize,
+    pub max_connections: usize,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub board: BoardConfig,
+    pub timers: Timers,
+    pub persistence: PersistenceConfig,
+    /// Serve TLS on both listeners; plain text when unset.
+    pub tls: Option<TlsConfig>,
+    /// No admin console when unset.
+    pub admin: Option<AdminConfig>,
+    /// Where Prometheus scrapes `/metrics`; no metrics endpoint when unset.
+    pub metrics_bind: Option<String>,
+    pub limits: LimitsConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:8080".to_string(),
+            websocket_bind: None,
+            max_rooms: 200,
+            max_connections: 500,
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Text,
+            board: BoardConfig::default(),
+            timers: Timers::default(),
+            persistence: PersistenceConfig::default(),
+            tls: None,
+            admin: None,
+            metrics_bind: None,
+            limits: LimitsConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn queue_timeout(&self) -> u64 {
+        self.timers.queue_timeout_secs
+    }
+
+    pub fn snapshot_interval(&self) -> Duration {
+        Duration::from_secs(self.timers.snapshot_interval_secs.max(1))
+    }
+
+    pub fn reconnect_grace(&self) -> Duration {
+        Duration::from_secs(self.timers.reconnect_grace_secs)
+    }
+
+    /// None when idle connections are kept open.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        match self.limits.idle_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    pub fn data_path(&self, file_name: &str) -> PathBuf {
+        self.persistence.data_dir.join(file_name)
+    }
+
+    fn load(path: &Path, required: bool) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|e| format!("invalid config {}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => Ok(Config::default()),
+            Err(e) => Err(format!("could not read config {}: {}", path.display(), e)),
+        }
+    }
+
+    fn validate(self) -> Result<Self, String> {
+        if self.board.initial_walls > MAX_INITIAL_WALLS {
+            Err(format!("board.initial_walls can be at most {}", MAX_INITIAL_WALLS))
+        } else if self.max_rooms == 0 || self.max_connections == 0 {
+            Err("max_rooms and max_connections must be at least 1".to_string())
+        } else if self.limits.max_message_bytes < MIN_MESSAGE_BYTES {
+            Err(format!(
+                "limits.max_message_bytes must be at least {}",
+                MIN_MESSAGE_BYTES
+            ))
+        } else if self.admin.as_ref().is_some_and(|admin| admin.token.trim().is_empty()) {
+            Err(format!(
+                "the admin console needs admin.token or ${}",
+                ADMIN_TOKEN_VARIABLE
+            ))
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+fn usage() -> String {
+    [
+        "usage: server [options]",
+        "  --config PATH          TOML config file (default server.toml, if present)",
+        "  --bind ADDR            address to listen on (default 127.0.0.1:8080)",
+        "  --websocket-bind ADDR  also accept WebSocket clients on this address",
+        "  --max-rooms N          rooms open at the same time (default 200)",
+        "  --max-connections N    clients connected at the same time (default 500)",
+        "  --initial-walls N      walls on a new board (default 6)",
+        "  --data-dir PATH        where accounts, history and snapshots are kept (default .)",
+        "  --no-persistence       do not snapshot rooms or keep a command log",
+        "  --log-level LEVEL      error, warn, info, debug or trace (default info)",
+        "  --log-format FORMAT    text or json (default text)",
+        "  --tls-cert PATH        serve TLS with this PEM certificate chain (needs --tls-key)",
+        "  --tls-key PATH         private key for --tls-cert",
+        "  --admin-bind ADDR      open the admin console on this address (keep it local)",
+        "  --metrics-bind ADDR    serve Prometheus metrics at http://ADDR/metrics",
+        "  --rooms-per-user N     rooms one user can have open (default 3, 0 for no limit)",
+        "  --idle-timeout SECS    close connections idle this long (default 300, 0 never)",
+        "  --no-limits            turn off the rate limits, for load tests and tournaments",
+        "",
+        "Command-line options override the config file. The admin console asks for",
+        "admin.token, or for $TRAPTHEMOUSE_ADMIN_TOKEN when that is set.",
+    ]
+    .join("\n")
+}
+
+/// Reads the config file, then applies the command-line overrides on top.
+pub fn parse_options() -> Result<Config, String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let config_path = args.iter().position(|arg| arg == "--config");
+    let mut config = match config_path {
+        Some(i) => {
+            let path = args
+                .get(i + 1)
+                .ok_or_else(|| format!("missing value for --config\n{}", usage()))?;
+            Config::load(Path::new(path), true)?
+        }
+        None => Config::load(Path::new(DEFAULT_CONFIG_PATH), false)?,
+    };
+
+    let mut tls_cert = None;
+    let mut tls_key = None;
+    let mut admin_bind = None;
+    let mut i = 0;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        match flag {
+            "--help" => return Err(usage()),
+            "--no-persistence" => {
+                config.persistence.enabled = false;
+                i += 1;
+                continue;
+            }
+            "--no-limits" => {
+                config.limits.commands_per_second = 0;
+                config.limits.updates_per_second = 0;
+                config.limits.user_commands_per_second = 0;
+                config.limits.failed_logins_per_minute = 0;
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+        let value = args
+            .get(i + 1)
+            .ok_or_else(|| format!("missing value for {}\n{}", flag, usage()))?;
+        match flag {
+            "--config" => {}
+            "--bind" => config.bind = value.clone(),
+            "--websocket-bind" => config.websocket_bind = Some(value.clone()),
+            "--max-rooms" => {
+                config.max_rooms = value.parse().map_err(|_| "--max-rooms expects a number")?
+            }
+            "--max-connections" => {
+                config.max_connections = value
+                    .parse()
+                    .map_err(|_| "--max-connections expects a number")?
+            }
+            "--initial-walls" => {
+                config.board.initial_walls = value
+                    .parse()
+                    .map_err(|_| "--initial-walls expects a number")?
+            }
+            "--data-dir" => config.persistence.data_dir = PathBuf::from(value),
+            "--log-level" => config.log_level = LogLevel::parse(value)?,
+            "--log-format" => config.log_format = LogFormat::parse(value)?,
+            "--tls-cert" => tls_cert = Some(PathBuf::from(value)),
+            "--tls-key" => tls_key = Some(PathBuf::from(value)),
+            "--admin-bind" => admin_bind = Some(value.clone()),
+            "--metrics-bind" => config.metrics_bind = Some(value.clone()),
+            "--rooms-per-user" => {
+                config.limits.rooms_per_user = value
+                    .parse()
+                    .map_err(|_| "--rooms-per-user expects a number")?
+            }
+            "--idle-timeout" => {
+                config.limits.idle_timeout_secs = value
+                    .parse()
+                    .map_err(|_| "--idle-timeout expects a number of seconds")?
+            }
+            _ => return Err(format!("unknown option '{}'\n{}", flag, usage())),
+        }
+        i += 2;
+    }
+    match (tls_cert, tls_key) {
+        (Some(cert_path), Some(key_path)) => config.tls = Some(TlsConfig { cert_path, key_path }),
+        (None, None) => {}
+        _ => return Err("--tls-cert and --tls-key go together".to_string()),
+    }
+    if let Some(bind) = admin_bind {
+        let token = config.admin.take().map(|admin| admin.token).unwrap_or_default();
+        config.admin = Some(AdminConfig { bind, token });
+    }
+    // The token is never taken from the command line, where other local
+    // users could read it.
+    if let Some(admin) = config.admin.as_mut() {
+        if let Ok(token) = std::env::var(ADMIN_TOKEN_VARIABLE) {
+            admin.token = token;
+        }
+    }
+    config.validate()
+}
@@ -0,0 +1,703 @@
+use crate::protocol::{parse_limit_error, Hello, PROTOCOL_MAJOR, PROTOCOL_MINOR, SHUTDOWN_NOTICE};
+use std::sync::Arc;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use native::Connection;
+#[cfg(target_arch = "wasm32")]
+pub use web::Connection;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Reconnecting(u32),
+    /// The first connection failed, or the server turned the client away or
+    /// shut down; no reconnects are attempted.
+    Failed(String),
+}
+
+/// Called whenever an event is sent, so a window can wake up and take it in.
+pub type Repaint = Arc<dyn Fn() + Send + Sync>;
+
+pub enum Event {
+    State(ConnectionState),
+    /// The server's answer to hello, after every (re)connect.
+    ServerHello(Hello),
+    /// A get_update reply that differs from the previous one.
+    Update(String),
+    /// The server refused a command for going over one of its limits, or
+    /// one that is not otherwise answered, such as a move out of turn.
+    Refused(String),
+}
+
+/// What this client can use, sent in its hello.
+const CAPABILITIES: &[&str] = &[
+    "chat",
+    "lobby_chat",
+    "matchmaking",
+    "private_rooms",
+    "ratings",
+    "history",
+    "replay",
+    "announcements",
+    "shutdown",
+    "replies",
+];
+
+/// Why the connection ended when the server shut down.
+const SHUT_DOWN: &str = "The server shut down. Log in again once it is back.";
+
+/// Checks the server's answer to hello. An error means this client cannot
+/// talk to that server at all.
+fn check_hello(line: &str) -> Result<Hello, String> {
+    let reply = parse_reply(line)?;
+    match Hello::parse(&reply) {
+        Some(hello) if hello.is_compatible() => Ok(hello),
+        Some(hello) => Err(format!(
+            "The server speaks protocol {} but this client {}.{}; please update the client",
+            hello.version(),
+            PROTOCOL_MAJOR,
+            PROTOCOL_MINOR
+        )),
+        None => Err(format!("Unexpected answer to hello: {}", line)),
+    }
+}
+
+fn parse_reply(line: &str) -> Result<String, String> {
+    if let Some((_, reason)) = parse_limit_error(line) {
+        return Err(reason.to_string());
+    }
+    match line.strip_prefix("OK:") {
+        Some(ok) => Ok(ok.to_string()),
+        None => Err(line.strip_prefix("ERR:").unwrap_or(line).to_string()),
+    }
+}
+
+/// The connection to the server, run on a background thread so the window
+/// never waits on the network. The thread polls get_update on its own and
+/// reconnects, logging in again, when the connection drops.
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use super::{
+        check_hello, parse_reply, ConnectionState, Event, Repaint, CAPABILITIES, SHUTDOWN_NOTICE,
+        SHUT_DOWN,
+    };
+    use crate::protocol::{parse_limit_error, parse_refusal, Hello};
+    use crate::tls_client;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tracing::{info, info_span, warn};
+
+    const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+    /// A server that takes longer than this to answer is treated as gone.
+    const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+    trait Stream: Read + Write + Send {}
+    impl<T: Read + Write + Send> Stream for T {}
+
+    enum ConnectError {
+        /// Worth trying again later.
+        Unreachable(String),
+        /// The server answered but will not talk to this client.
+        Rejected(String),
+    }
+
+    enum Outgoing {
+        Command(String),
+        Request(String, Sender<Result<String, String>>),
+    }
+
+    pub struct Connection {
+        pub address: String,
+        pub trusted_ca: String,
+        outgoing: Sender<Outgoing>,
+        pub events: Receiver<Event>,
+    }
+
+    impl Connection {
+        /// `address` is host:port, or tls://host:port for a TLS server. The
+        /// server certificate is checked against the PEM file `trusted_ca`, or
+        /// the usual web roots when that is empty.
+        pub fn open(
+            address: String,
+            trusted_ca: String,
+            update_interval: Duration,
+            repaint: Repaint,
+        ) -> Self {
+            let (outgoing, commands) = mpsc::channel();
+            let (events_sender, events) = mpsc::channel();
+            let worker = Worker {
+                address: address.clone(),
+                trusted_ca: trusted_ca.clone(),
+                update_interval,
+                commands,
+                events: events_sender,
+                repaint,
+                login: None,
+                last_update: String::new(),
+                read_buffer: Vec::new(),
+                shut_down: false,
+            };
+            let span = info_span!("connection", %address);
+            thread::spawn(move || span.in_scope(|| worker.run()));
+            Self {
+                address,
+                trusted_ca,
+                outgoing,
+                events,
+            }
+        }
+
+        /// Sends a command the server does not answer.
+        pub fn send(&self, message: &str) {
+            if self.outgoing.send(Outgoing::Command(message.to_string())).is_err() {
+                warn!("no connection for a command");
+            }
+        }
+
+        /// Sends a command that the server answers with a single OK:/ERR: line.
+        /// The answer arrives on the returned channel.
+        pub fn request(&self, message: &str) -> Receiver<Result<String, String>> {
+            let (reply, answer) = mpsc::channel();
+            // If the thread is gone, `reply` is dropped and the caller sees a
+            // disconnected channel.
+            let _ = self.outgoing.send(Outgoing::Request(message.to_string(), reply));
+            answer
+        }
+    }
+
+    struct Worker {
+        address: String,
+        trusted_ca: String,
+        update_interval: Duration,
+        commands: Receiver<Outgoing>,
+        events: Sender<Event>,
+        repaint: Repaint,
+        /// The last login that succeeded, sent again after a reconnect.
+        login: Option<String>,
+        last_update: String,
+        read_buffer: Vec<u8>,
+        /// Set when the server said it is shutting down; no reconnects then.
+        shut_down: bool,
+    }
+
+    impl Worker {
+        fn run(mut self) {
+            self.set_state(ConnectionState::Connecting);
+            let mut stream = match self.connect() {
+                Ok(stream) => stream,
+                Err(ConnectError::Unreachable(e) | ConnectError::Rejected(e)) => {
+                    self.set_state(ConnectionState::Failed(e));
+                    return;
+                }
+            };
+
+            loop {
+                self.set_state(ConnectionState::Connected);
+                match self.serve(stream.as_mut()) {
+                    Ok(()) => return,
+                    Err(_) if self.shut_down => {
+                        self.set_state(ConnectionState::Failed(SHUT_DOWN.to_string()));
+                        return;
+                    }
+                    Err(e) => warn!(error = %e, "the server stopped the connection"),
+                }
+
+                let mut attempt = 0;
+                stream = loop {
+                    attempt += 1;
+                    self.set_state(ConnectionState::Reconnecting(attempt));
+                    thread::sleep(RECONNECT_DELAY);
+                    match self.connect() {
+                        Ok(stream) => break stream,
+                        Err(ConnectError::Unreachable(_)) => {}
+                        Err(ConnectError::Rejected(e)) => {
+                            self.set_state(ConnectionState::Failed(e));
+                            return;
+                        }
+                    }
+                };
+                if let Some(login) = self.login.clone() {
+                    if let Err(e) = self.round_trip(stream.as_mut(), &login, "OK:") {
+                        warn!(error = %e, "login after reconnect failed");
+                    }
+                }
+            }
+        }
+
+        /// Opens the connection and says hello.
+        fn connect(&mut self) -> Result<Box<dyn Stream>, ConnectError> {
+            let mut stream = self.open_stream().map_err(ConnectError::Unreachable)?;
+            self.read_buffer.clear();
+            let hello = Hello::new(CAPABILITIES).to_command();
+            let line = self
+                .round_trip(stream.as_mut(), &hello, "OK:")
+                .map_err(|e| ConnectError::Unreachable(format!("No answer to hello: {}", e)))?;
+            let server = check_hello(&line).map_err(ConnectError::Rejected)?;
+            let _ = self.events.send(Event::ServerHello(server));
+            Ok(stream)
+        }
+
+        fn open_stream(&self) -> Result<Box<dyn Stream>, String> {
+            let (tls, address) = match self.address.strip_prefix("tls://") {
+                Some(address) => (true, address),
+                None => (false, self.address.as_str()),
+            };
+            let stream = TcpStream::connect(address)
+                .map_err(|e| format!("Could not connect to {}: {}", address, e))?;
+            stream
+                .set_read_timeout(Some(READ_TIMEOUT))
+                .map_err(|e| e.to_string())?;
+            if !tls {
+                return Ok(Box::new(stream));
+            }
+
+            Ok(Box::new(tls_client::connect(
+                stream,
+                address,
+                &self.trusted_ca,
+            )?))
+        }
+
+        fn set_state(&self, state: ConnectionState) {
+            info!(?state, "connection state");
+            let _ = self.events.send(Event::State(state));
+            (self.repaint)();
+        }
+
+        /// Passes the window's commands on and polls for updates until the
+        /// connection fails (Err) or the window drops the connection (Ok).
+        fn serve(&mut self, stream: &mut dyn Stream) -> std::io::Result<()> {
+            let mut next_update = Instant::now();
+            loop {
+                let wait = next_update.saturating_duration_since(Instant::now());
+                match self.commands.recv_timeout(wait) {
+                    Ok(Outgoing::Command(message)) => {
+                        if message.trim() == "logout" {
+                            self.login = None;
+                        }
+                        write_command(stream, &message)?;
+                    }
+                    Ok(Outgoing::Request(message, reply)) => {
+                        let line = self.round_trip(stream, &message, "OK:")?;
+                        let result = parse_reply(&line);
+                        if message.trim().starts_with("login") && result.is_ok() {
+                            self.login = Some(message);
+                        }
+                        let _ = reply.send(result);
+                        (self.repaint)();
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        let update = self.round_trip(stream, "get_update", "{")?;
+                        if update.starts_with('{') && update != self.last_update {
+                            self.last_update = update.clone();
+                            let _ = self.events.send(Event::Update(update));
+                            (self.repaint)();
+                        }
+                        next_update = Instant::now() + self.update_interval;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                }
+            }
+        }
+
+        /// Writes `message` and returns the first line of the answer that starts
+        /// with `expected`, or the limit error or refusal naming it. Other lines, such as
+        /// echoed unknown commands, are skipped so one stray line cannot shift
+        /// every later answer.
+        fn round_trip(
+            &mut self,
+            stream: &mut dyn Stream,
+            message: &str,
+            expected: &str,
+        ) -> std::io::Result<String> {
+            write_command(stream, message)?;
+            loop {
+                let line = self.read_line(stream)?;
+                if line == SHUTDOWN_NOTICE {
+                    self.shut_down = true;
+                    return Err(std::io::ErrorKind::ConnectionAborted.into());
+                }
+                if let Some((command, reason)) =
+                    parse_limit_error(&line).or_else(|| parse_refusal(&line))
+                {
+                    warn!(command, reason, "refused by the server");
+                    let _ = self.events.send(Event::Refused(reason.to_string()));
+                    (self.repaint)();
+                    if message.split_whitespace().next() == Some(command) {
+                        return Ok(line);
+                    }
+                    continue;
+                }
+                if line.starts_with(expected) || (expected == "OK:" && line.starts_with("ERR:")) {
+                    return Ok(line);
+                }
+            }
+        }
+
+        fn read_line(&mut self, stream: &mut dyn Stream) -> std::io::Result<String> {
+            let mut buffer = [0; 2048];
+            loop {
+                if let Some(end) = self.read_buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = self.read_buffer.drain(..=end).collect();
+                    return Ok(String::from_utf8_lossy(&line).trim().to_string());
+                }
+                match stream.read(&mut buffer)? {
+                    0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                    n => self.read_buffer.extend_from_slice(&buffer[..n]),
+                }
+            }
+        }
+    }
+
+    /// Commands go out one per line.
+    fn write_command(stream: &mut dyn Stream, message: &str) -> std::io::Result<()> {
+        stream.write_all(format!("{}\n", message).as_bytes())
+    }
+}
+
+/// The browser build talks WebSocket instead. There are no threads, so the
+/// socket callbacks and a JS timer drive the same polling and reconnects.
+#[cfg(target_arch = "wasm32")]
+mod web {
+    use super::{
+        check_hello, parse_reply, ConnectionState, Event, Repaint, CAPABILITIES, SHUTDOWN_NOTICE,
+        SHUT_DOWN,
+    };
+    use crate::protocol::{parse_limit_error, parse_refusal, Hello};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::time::Duration;
+    use wasm_bindgen::closure::Closure;
+    use wasm_bindgen::JsCast;
+    use tracing::{info, warn};
+    use web_sys::{CloseEvent, MessageEvent, WebSocket};
+
+    const RECONNECT_DELAY_MS: i32 = 2000;
+
+    type Reply = Sender<Result<String, String>>;
+
+    type Handlers = (
+        Closure<dyn FnMut()>,
+        Closure<dyn FnMut(MessageEvent)>,
+        Closure<dyn FnMut(CloseEvent)>,
+    );
+
+    pub struct Connection {
+        pub address: String,
+        /// Kept only so both builds look the same to the window.
+        pub trusted_ca: String,
+        socket: Rc<RefCell<Socket>>,
+        pub events: Receiver<Event>,
+        poll_timer: i32,
+        _poll: Closure<dyn FnMut()>,
+    }
+
+    struct Socket {
+        url: String,
+        websocket: Option<WebSocket>,
+        handlers: Option<Handlers>,
+        /// Open and past hello.
+        connected: bool,
+        awaiting_hello: bool,
+        ever_connected: bool,
+        closed: bool,
+        attempt: u32,
+        events: Sender<Event>,
+        repaint: Repaint,
+        /// Commands written once the socket is open.
+        outbox: Vec<String>,
+        /// Requests waiting for their OK:/ERR: line, oldest first.
+        waiting: VecDeque<(String, Option<Reply>)>,
+        awaiting_update: bool,
+        /// The last login that succeeded, sent again after a reconnect.
+        login: Option<String>,
+        last_update: String,
+    }
+
+    impl Connection {
+        /// The browser checks wss:// certificates itself, so `trusted_ca`
+        /// is not used here.
+        pub fn open(
+            address: String,
+            trusted_ca: String,
+            update_interval: Duration,
+            repaint: Repaint,
+        ) -> Self {
+            let url = if address.contains("://") {
+                address.clone()
+            } else {
+                format!("ws://{}", address)
+            };
+            let (events_sender, events) = mpsc::channel();
+            let socket = Rc::new(RefCell::new(Socket {
+                url,
+                websocket: None,
+                handlers: None,
+                connected: false,
+                awaiting_hello: false,
+                ever_connected: false,
+                closed: false,
+                attempt: 0,
+                events: events_sender,
+                repaint,
+                outbox: Vec::new(),
+                waiting: VecDeque::new(),
+                awaiting_update: false,
+                login: None,
+                last_update: String::new(),
+            }));
+            socket.borrow().set_state(ConnectionState::Connecting);
+            open_socket(&socket);
+
+            let poll = {
+                let socket = Rc::clone(&socket);
+                Closure::<dyn FnMut()>::new(move || socket.borrow_mut().poll())
+            };
+            let poll_timer = web_sys::window()
+                .and_then(|window| {
+                    window
+                        .set_interval_with_callback_and_timeout_and_arguments_0(
+                            poll.as_ref().unchecked_ref(),
+                            update_interval.as_millis() as i32,
+                        )
+                        .ok()
+                })
+                .unwrap_or(0);
+            Self {
+                address,
+                trusted_ca,
+                socket,
+                events,
+                poll_timer,
+                _poll: poll,
+            }
+        }
+
+        /// Sends a command the server does not answer.
+        pub fn send(&self, message: &str) {
+            let mut socket = self.socket.borrow_mut();
+            if message.trim() == "logout" {
+                socket.login = None;
+            }
+            socket.write(message);
+        }
+
+        /// Sends a command that the server answers with a single OK:/ERR: line.
+        /// The answer arrives on the returned channel.
+        pub fn request(&self, message: &str) -> Receiver<Result<String, String>> {
+            let (reply, answer) = mpsc::channel();
+            let mut socket = self.socket.borrow_mut();
+            // A closed connection drops `reply`, so the caller sees a
+            // disconnected channel.
+            if !socket.closed {
+                socket.waiting.push_back((message.to_string(), Some(reply)));
+                socket.write(message);
+            }
+            answer
+        }
+    }
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            if let Some(window) = web_sys::window() {
+                window.clear_interval_with_handle(self.poll_timer);
+            }
+            let mut socket = self.socket.borrow_mut();
+            socket.closed = true;
+            if let Some(websocket) = socket.websocket.take() {
+                websocket.set_onopen(None);
+                websocket.set_onmessage(None);
+                websocket.set_onclose(None);
+                let _ = websocket.close();
+            }
+            socket.handlers = None;
+        }
+    }
+
+    impl Socket {
+        fn set_state(&self, state: ConnectionState) {
+            info!(?state, "connection state");
+            let _ = self.events.send(Event::State(state));
+            (self.repaint)();
+        }
+
+        fn write(&mut self, message: &str) {
+            match &self.websocket {
+                _ if self.closed => warn!("no connection for a command"),
+                Some(websocket) if self.connected => {
+                    if let Err(e) = websocket.send_with_str(message) {
+                        warn!(error = ?e, "could not send to the server");
+                    }
+                }
+                _ => self.outbox.push(message.to_string()),
+            }
+        }
+
+        fn poll(&mut self) {
+            if self.connected && !self.awaiting_update {
+                self.awaiting_update = true;
+                self.write("get_update");
+            }
+        }
+
+        fn opened(&mut self) {
+            self.awaiting_hello = true;
+            if let Some(websocket) = &self.websocket {
+                let _ = websocket.send_with_str(&Hello::new(CAPABILITIES).to_command());
+            }
+        }
+
+        fn greeted(&mut self, line: &str) {
+            self.awaiting_hello = false;
+            let server = match check_hello(line) {
+                Ok(server) => server,
+                Err(e) => {
+                    self.fail(e);
+                    return;
+                }
+            };
+            let _ = self.events.send(Event::ServerHello(server));
+            self.connected = true;
+            self.ever_connected = true;
+            self.attempt = 0;
+            self.set_state(ConnectionState::Connected);
+            if let Some(login) = self.login.clone() {
+                self.waiting.push_front((login.clone(), None));
+                self.write(&login);
+            }
+            for message in std::mem::take(&mut self.outbox) {
+                self.write(&message);
+            }
+        }
+
+        /// Closes the connection for good; no reconnects are attempted.
+        fn fail(&mut self, e: String) {
+            self.closed = true;
+            self.outbox.clear();
+            self.waiting.clear();
+            if let Some(websocket) = &self.websocket {
+                let _ = websocket.close();
+            }
+            self.set_state(ConnectionState::Failed(e));
+        }
+
+        fn received(&mut self, text: &str) {
+            let line = text.trim();
+            if self.awaiting_hello {
+                self.greeted(line);
+            } else if line == SHUTDOWN_NOTICE {
+                self.fail(SHUT_DOWN.to_string());
+            } else if line.starts_with('{') {
+                self.awaiting_update = false;
+                if line != self.last_update {
+                    self.last_update = line.to_string();
+                    let _ = self.events.send(Event::Update(line.to_string()));
+                    (self.repaint)();
+                }
+            } else if let Some((command, reason)) =
+                parse_limit_error(line).or_else(|| parse_refusal(line))
+            {
+                // Also sent for commands that get no answer, so it only
+                // settles the oldest request when it names that command.
+                warn!(command, reason, "refused by the server");
+                let _ = self.events.send(Event::Refused(reason.to_string()));
+                let refuses_request = self
+                    .waiting
+                    .front()
+                    .is_some_and(|(message, _)| message.split_whitespace().next() == Some(command));
+                if command == "get_update" {
+                    self.awaiting_update = false;
+                } else if refuses_request {
+                    if let Some((_, Some(reply))) = self.waiting.pop_front() {
+                        let _ = reply.send(parse_reply(line));
+                    }
+                }
+                (self.repaint)();
+            } else if line.starts_with("OK:") || line.starts_with("ERR:") {
+                if let Some((message, reply)) = self.waiting.pop_front() {
+                    let result = parse_reply(line);
+                    if message.trim().starts_with("login") && result.is_ok() {
+                        self.login = Some(message);
+                    }
+                    if let Some(reply) = reply {
+                        let _ = reply.send(result);
+                    }
+                    (self.repaint)();
+                }
+            }
+        }
+    }
+
+    fn open_socket(socket: &Rc<RefCell<Socket>>) {
+        let url = socket.borrow().url.clone();
+        let websocket = match WebSocket::new(&url) {
+            Ok(websocket) => websocket,
+            Err(_) => {
+                let mut socket = socket.borrow_mut();
+                socket.closed = true;
+                socket.set_state(ConnectionState::Failed(format!("Invalid server address {}", url)));
+                return;
+            }
+        };
+
+        let on_open = {
+            let socket = Rc::clone(socket);
+            Closure::<dyn FnMut()>::new(move || socket.borrow_mut().opened())
+        };
+        let on_message = {
+            let socket = Rc::clone(socket);
+            Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+                if let Some(text) = event.data().as_string() {
+                    socket.borrow_mut().received(&text);
+                }
+            })
+        };
+        let on_close = {
+            let socket = Rc::clone(socket);
+            Closure::<dyn FnMut(CloseEvent)>::new(move |_: CloseEvent| closed(&socket))
+        };
+        websocket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        websocket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        websocket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        let mut socket = socket.borrow_mut();
+        socket.websocket = Some(websocket);
+        socket.handlers = Some((on_open, on_message, on_close));
+    }
+
+    fn closed(socket: &Rc<RefCell<Socket>>) {
+        let mut state = socket.borrow_mut();
+        state.connected = false;
+        state.awaiting_hello = false;
+        state.awaiting_update = false;
+        state.waiting.clear();
+        if state.closed {
+            return;
+        }
+        if !state.ever_connected {
+            state.closed = true;
+            state.outbox.clear();
+            let error = format!("Could not connect to {}", state.url);
+            state.set_state(ConnectionState::Failed(error));
+            return;
+        }
+        state.attempt += 1;
+        state.set_state(ConnectionState::Reconnecting(state.attempt));
+        drop(state);
+
+        let socket = Rc::clone(socket);
+        let retry = Closure::once_into_js(move || open_socket(&socket));
+        if let Some(window) = web_sys::window() {
+            let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(
+                retry.unchecked_ref(),
+                RECONNECT_DELAY_MS,
+            );
+        }
+    }
+}
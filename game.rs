@@ -0,0 +1,486 @@
+use rand::prelude::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum GameState {
+    Waiting,
+    InGame,
+    GameOver,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum Turn {
+    MousePlayer,
+    TrapperPlayer,
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+
+pub enum RoomType {
+    SinglePlayer,
+    MultiPlayer,
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+
+pub enum Difficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+pub const MAX_INITIAL_WALLS: u32 = 30;
+pub const MAX_CHAT_HISTORY: usize = 50;
+pub const MAX_CHAT_LENGTH: usize = 200;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct BoardConfig {
+    pub initial_walls: u32,
+}
+
+impl Default for BoardConfig {
+    fn default() -> Self {
+        Self { initial_walls: 6 }
+    }
+}
+
+/// One move of a game, in the order it was played. Together with the
+/// starting walls this is enough to replay a finished game.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum GameMove {
+    Mouse(u32, u32),
+    Wall(u32, u32),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChatMessage {
+    pub username: String,
+    pub text: String,
+}
+
+/// Fields added after the first protocol version default when missing, so
+/// rooms still load from older snapshots and older servers.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Room {
+    pub room_id: u32,
+    pub room_name: String,
+    pub mouse_player: Option<String>,
+    pub trapper_player: Option<String>,
+    #[serde(default)]
+    pub spectators: Vec<String>,
+    #[serde(default)]
+    pub chat: Vec<ChatMessage>,
+    pub game_state: GameState,
+    pub mouse_position: (u32, u32),
+    pub walls: Vec<(u32, u32)>,
+    #[serde(default)]
+    pub starting_walls: Vec<(u32, u32)>,
+    #[serde(default)]
+    pub moves: Vec<GameMove>,
+    pub turn: Turn,
+    pub winner: Option<Turn>,
+    pub room_type: RoomType,
+    pub game_difficulty: Option<Difficulty>,
+    pub mouse_player_exited: bool,
+    pub trapper_player_exited: bool,
+    #[serde(default)]
+    pub rematch_votes: Vec<String>,
+    #[serde(default)]
+    pub rematch_swap: bool,
+    #[serde(default)]
+    pub series_score: Vec<(String, u32)>,
+    #[serde(default)]
+    pub mouse_ready: bool,
+    #[serde(default)]
+    pub trapper_ready: bool,
+    #[serde(default)]
+    pub started_at: Option<u64>,
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
+    pub board: BoardConfig,
+    #[serde(default)]
+    pub rated: bool,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+fn random_walls(count: u32) -> Vec<(u32, u32)> {
+    let mut rng = rand::thread_rng();
+    let mut rand_walls = Vec::new();
+    while rand_walls.len() < count.min(MAX_INITIAL_WALLS) as usize {
+        let x = rng.gen_range(0..11);
+        let y = rng.gen_range(0..11);
+        if (x, y) != (5, 5) && !rand_walls.contains(&(x, y)) {
+            rand_walls.push((x, y));
+        }
+    }
+    rand_walls
+}
+
+impl Room {
+    pub fn new(room_id: u32, room_name: String) -> Self {
+        Room::with_board(room_id, room_name, BoardConfig::default())
+    }
+
+    pub fn with_board(room_id: u32, room_name: String, board: BoardConfig) -> Self {
+        let walls = random_walls(board.initial_walls);
+
+        Self {
+            room_id,
+            room_name,
+            mouse_player: None,
+            trapper_player: None,
+            spectators: Vec::new(),
+            chat: Vec::new(),
+            game_state: GameState::Waiting,
+            mouse_position: (5, 5),
+            starting_walls: walls.clone(),
+            walls,
+            moves: Vec::new(),
+            turn: Turn::TrapperPlayer,
+            winner: None,
+            room_type: RoomType::MultiPlayer,
+            game_difficulty: None,
+            mouse_player_exited: false,
+            trapper_player_exited: false,
+            rematch_votes: Vec::new(),
+            rematch_swap: false,
+            series_score: Vec::new(),
+            mouse_ready: false,
+            trapper_ready: false,
+            started_at: None,
+            locked: false,
+            board,
+            rated: false,
+        }
+    }
+
+    /// Moves the room into `InGame` and stamps the start time in seconds since the epoch.
+    pub fn start(&mut self) {
+        self.game_state = GameState::InGame;
+        self.started_at = Some(unix_now());
+        self.starting_walls = self.walls.clone();
+        self.moves.clear();
+    }
+
+    /// Fails unless a game is being played and it is `side`'s turn.
+    pub fn check_turn(&self, side: &Turn) -> Result<(), String> {
+        if self.game_state != GameState::InGame {
+            Err(format!("room {} has no game in progress", self.room_id))
+        } else if self.turn != *side {
+            Err("not your turn".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fails unless the mouse may move to (x, y) now: on its turn, to a free
+    /// hex next to it.
+    pub fn check_mouse_move(&self, x: u32, y: u32) -> Result<(), String> {
+        self.check_turn(&Turn::MousePlayer)?;
+        let (mouse_x, mouse_y) = self.mouse_position;
+        if self.posib_moves(mouse_x, mouse_y).contains(&(x, y)) {
+            Ok(())
+        } else {
+            Err(format!("the mouse cannot move to {} {}", x, y))
+        }
+    }
+
+    /// Fails unless a wall may go on (x, y) now: on the trapper's turn, on a
+    /// free hex of the board.
+    pub fn check_wall(&self, x: u32, y: u32) -> Result<(), String> {
+        self.check_turn(&Turn::TrapperPlayer)?;
+        if x >= 11 || y >= 11 {
+            Err(format!("{} {} is off the board", x, y))
+        } else if (x, y) == self.mouse_position || self.walls.contains(&(x, y)) {
+            Err(format!("{} {} is not free", x, y))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn move_mouse(&mut self, x: u32, y: u32) {
+        self.mouse_position = (x, y);
+        self.moves.push(GameMove::Mouse(x, y));
+        self.turn = Turn::TrapperPlayer;
+    }
+
+    pub fn place_wall(&mut self, x: u32, y: u32) {
+        self.walls.push((x, y));
+        self.moves.push(GameMove::Wall(x, y));
+        self.turn = Turn::MousePlayer;
+    }
+
+    /// Credits the winner of the game that just ended in the series score.
+    pub fn record_result(&mut self) {
+        let winner = match self.winner {
+            Some(Turn::MousePlayer) => self.mouse_player.clone(),
+            Some(Turn::TrapperPlayer) => self.trapper_player.clone(),
+            None => None,
+        };
+        if let Some(winner) = winner {
+            match self.series_score.iter_mut().find(|(name, _)| *name == winner) {
+                Some((_, wins)) => *wins += 1,
+                None => self.series_score.push((winner, 1)),
+            }
+        }
+    }
+
+    /// Starts a new game in the same room with the same players.
+    pub fn reset_for_rematch(&mut self) {
+        if self.rematch_swap {
+            std::mem::swap(&mut self.mouse_player, &mut self.trapper_player);
+        }
+        self.walls = random_walls(self.board.initial_walls);
+        self.mouse_position = (5, 5);
+        self.turn = Turn::TrapperPlayer;
+        self.winner = None;
+        self.start();
+        self.mouse_player_exited = false;
+        self.trapper_player_exited = false;
+        self.rematch_votes.clear();
+        self.rematch_swap = false;
+    }
+
+    pub fn ai_move(&mut self) {
+        let (mouse_x, mouse_y) = self.mouse_position;
+        let posib_moves = self.posib_moves(mouse_x, mouse_y);
+
+        match self.game_difficulty.as_ref() {
+            Some(Difficulty::Easy) => {
+                if let Some(&(new_x, new_y)) = posib_moves.choose(&mut rand::thread_rng()) {
+                    self.move_mouse(new_x, new_y);
+                } else {
+                    debug!(room_id = self.room_id, "the AI mouse has no moves left");
+                    self.winner = Some(Turn::TrapperPlayer);
+                }
+            }
+            Some(Difficulty::Medium) => {
+                let medium_moves: Vec<(u32, u32)> = posib_moves
+                    .iter()
+                    .filter(|&&hex| !self.danger_hex(hex))
+                    .cloned()
+                    .collect();
+
+                if !medium_moves.is_empty() {
+                    if let Some(&(new_x, new_y)) = medium_moves.choose(&mut rand::thread_rng()) {
+                        self.move_mouse(new_x, new_y);
+                    } else {
+                        debug!(room_id = self.room_id, "the AI mouse has no moves left");
+                        self.winner = Some(Turn::TrapperPlayer);
+                    }
+                } else if let Some(&(new_x, new_y)) = posib_moves.choose(&mut rand::thread_rng()) {
+                        self.move_mouse(new_x, new_y);
+                    } else {
+                        debug!(room_id = self.room_id, "the AI mouse has no moves left");
+                        self.winner = Some(Turn::TrapperPlayer);
+                    
+                }
+            }
+            Some(Difficulty::Hard) => {}
+            None => {
+                warn!(
+                    room_id = self.room_id,
+                    "AI move in a room without a difficulty"
+                );
+            }
+        }
+    }
+
+    fn danger_hex(&self, hex: (u32, u32)) -> bool {
+        let (x, y) = hex;
+        if x > 0 && y > 0 {
+            self.walls.iter().any(|&(wx, wy)| {
+                (wx == x && (wy == y + 1 || wy == y - 1))
+                    || (wy == y && (wx == x + 1 || wx == x - 1))
+            })
+        } else {
+            false
+        }
+    }
+
+    pub fn is_spectator(&self, username: &str) -> bool {
+        self.spectators.iter().any(|spectator| spectator == username)
+    }
+
+    pub fn is_member(&self, username: &str) -> bool {
+        self.mouse_player.as_deref() == Some(username)
+            || self.trapper_player.as_deref() == Some(username)
+            || self.is_spectator(username)
+    }
+
+    pub fn push_chat(&mut self, username: &str, text: &str) {
+        self.chat.push(ChatMessage {
+            username: username.to_string(),
+            text: text.to_string(),
+        });
+        if self.chat.len() > MAX_CHAT_HISTORY {
+            let excess = self.chat.len() - MAX_CHAT_HISTORY;
+            self.chat.drain(..excess);
+        }
+    }
+
+    pub fn mouse_escaped(&self) -> bool {
+        let (x, y) = self.mouse_position;
+        x == 0 || x == 10 || y == 0 || y == 10
+    }
+
+    pub fn mouse_trapped(&self) -> bool {
+        let (x, y) = self.mouse_position;
+        self.posib_moves(x, y).is_empty()
+    }
+
+    /// The side the board shows as the winner: the mouse once it reaches the
+    /// edge, the trapper once it has nowhere left to go.
+    pub fn decided_winner(&self) -> Option<Turn> {
+        if self.mouse_escaped() {
+            Some(Turn::MousePlayer)
+        } else if self.mouse_trapped() {
+            Some(Turn::TrapperPlayer)
+        } else {
+            None
+        }
+    }
+
+    pub fn posib_moves(&self, mouse_x: u32, mouse_y: u32) -> Vec<(u32, u32)> {
+        let direction_par: Vec<(i32, i32)> =
+            vec![(0, 1), (0, -1), (1, -1), (1, 0), (-1, -1), (-1, 0)];
+        let direction_impar: Vec<(i32, i32)> =
+            vec![(0, 1), (0, -1), (1, 0), (1, 1), (-1, 0), (-1, 1)];
+        let direction: &Vec<(i32, i32)> = if mouse_x % 2 == 0 {
+            &direction_par
+        } else {
+            &direction_impar
+        };
+
+        let mut new_moves = Vec::new();
+
+        for &(x, y) in direction {
+            let new_x = mouse_x as i32 + x;
+            let new_y = mouse_y as i32 + y;
+
+            if new_x >= 0 && new_y >= 0 && new_x < 11 && new_y < 11 && !self.walls.contains(&(new_x as u32, new_y as u32)) {
+                    new_moves.push((new_x as u32, new_y as u32));
+                }
+        }
+
+        new_moves
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BoardConfig, GameMove, GameState, Room, Turn};
+
+    /// A room with an empty board and a game in progress, trapper to play.
+    fn started() -> Room {
+        let mut room = Room::with_board(1, "test".to_string(), BoardConfig { initial_walls: 0 });
+        room.mouse_player = Some("alice".to_string());
+        room.trapper_player = Some("bob".to_string());
+        room.start();
+        room
+    }
+
+    #[test]
+    fn no_one_plays_before_the_game_starts() {
+        let room = Room::with_board(1, "test".to_string(), BoardConfig { initial_walls: 0 });
+        assert!(room.check_turn(&Turn::TrapperPlayer).is_err());
+        assert!(room.check_wall(0, 0).is_err());
+        assert!(room.check_mouse_move(5, 6).is_err());
+    }
+
+    #[test]
+    fn trapper_and_mouse_take_turns() {
+        let mut room = started();
+        assert!(room.check_turn(&Turn::TrapperPlayer).is_ok());
+        assert_eq!(
+            room.check_turn(&Turn::MousePlayer),
+            Err("not your turn".to_string())
+        );
+        assert!(room.check_mouse_move(5, 6).is_err());
+
+        room.check_wall(0, 0).expect("trapper opens");
+        room.place_wall(0, 0);
+        assert!(room.check_wall(1, 1).is_err());
+        room.check_mouse_move(5, 6).expect("mouse answers");
+        room.move_mouse(5, 6);
+
+        assert!(room.check_turn(&Turn::TrapperPlayer).is_ok());
+        assert!(room.moves == vec![GameMove::Wall(0, 0), GameMove::Mouse(5, 6)]);
+    }
+
+    #[test]
+    fn walls_go_only_on_free_hexes_of_the_board() {
+        let mut room = started();
+        room.walls.push((3, 3));
+        assert!(room.check_wall(11, 0).is_err());
+        assert!(room.check_wall(0, 11).is_err());
+        assert!(room.check_wall(5, 5).is_err());
+        assert!(room.check_wall(3, 3).is_err());
+        assert!(room.check_wall(3, 4).is_ok());
+    }
+
+    #[test]
+    fn the_mouse_moves_only_to_free_neighbours() {
+        let mut room = started();
+        room.walls.push((4, 5));
+        room.turn = Turn::MousePlayer;
+        for free in [(5, 6), (5, 4), (6, 5), (6, 6), (4, 6)] {
+            assert!(room.check_mouse_move(free.0, free.1).is_ok(), "{:?}", free);
+        }
+        assert!(room.check_mouse_move(4, 5).is_err());
+        assert!(room.check_mouse_move(5, 5).is_err());
+        assert!(room.check_mouse_move(5, 7).is_err());
+    }
+
+    #[test]
+    fn the_board_decides_escapes_and_traps() {
+        let mut room = started();
+        assert!(room.decided_winner().is_none());
+
+        room.mouse_position = (0, 4);
+        assert!(room.decided_winner() == Some(Turn::MousePlayer));
+
+        room.mouse_position = (5, 5);
+        room.walls = vec![(5, 6), (5, 4), (6, 5), (6, 6), (4, 5), (4, 6)];
+        assert!(room.decided_winner() == Some(Turn::TrapperPlayer));
+    }
+
+    #[test]
+    fn a_rematch_swaps_sides_only_when_asked() {
+        let mut room = started();
+        room.game_state = GameState::GameOver;
+        room.reset_for_rematch();
+        assert_eq!(room.mouse_player.as_deref(), Some("alice"));
+
+        room.game_state = GameState::GameOver;
+        room.rematch_swap = true;
+        room.reset_for_rematch();
+        assert_eq!(room.mouse_player.as_deref(), Some("bob"));
+        assert_eq!(room.trapper_player.as_deref(), Some("alice"));
+        assert!(room.game_state == GameState::InGame);
+        assert!(room.turn == Turn::TrapperPlayer);
+        assert!(!room.rematch_swap);
+    }
+
+    #[test]
+    fn the_series_score_credits_the_winner() {
+        let mut room = started();
+        room.winner = Some(Turn::TrapperPlayer);
+        room.record_result();
+        room.record_result();
+        room.winner = Some(Turn::MousePlayer);
+        room.record_result();
+        assert_eq!(
+            room.series_score,
+            vec![("bob".to_string(), 2), ("alice".to_string(), 1)]
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tracing::{error, info};
+
+use crate::game::{unix_now, BoardConfig, GameMove, Room, Turn};
+
+/// How a finished game came to an end. Forfeits and disconnects name the
+/// player who left.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum GameEnding {
+    Finished,
+    Forfeit(String),
+    Disconnect(String),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MatchRecord {
+    pub match_id: u32,
+    pub room_name: String,
+    pub mouse_player: String,
+    pub trapper_player: String,
+    pub winner: Option<Turn>,
+    pub ending: GameEnding,
+    pub rated: bool,
+    pub board: BoardConfig,
+    pub started_at: u64,
+    pub ended_at: u64,
+    pub starting_walls: Vec<(u32, u32)>,
+    pub moves: Vec<GameMove>,
+}
+
+impl MatchRecord {
+    /// Builds the record of the game that just ended in a multiplayer room.
+    /// The match id is assigned when the record is added to the history.
+    pub fn from_room(room: &Room, ending: GameEnding) -> Option<Self> {
+        Some(Self {
+            match_id: 0,
+            room_name: room.room_name.clone(),
+            mouse_player: room.mouse_player.clone()?,
+            trapper_player: room.trapper_player.clone()?,
+            winner: room.winner.clone(),
+            ending,
+            // A game against oneself never counts toward ratings.
+            rated: room.rated && room.mouse_player != room.trapper_player,
+            board: room.board.clone(),
+            started_at: room.started_at.unwrap_or(0),
+            ended_at: unix_now(),
+            starting_walls: room.starting_walls.clone(),
+            moves: room.moves.clone(),
+        })
+    }
+
+    fn role_of(&self, username: &str) -> Option<Turn> {
+        if self.mouse_player == username {
+            Some(Turn::MousePlayer)
+        } else if self.trapper_player == username {
+            Some(Turn::TrapperPlayer)
+        } else {
+            None
+        }
+    }
+
+    fn count_moves(&self, role: &Turn) -> usize {
+        self.moves
+            .iter()
+            .filter(|game_move| match game_move {
+                GameMove::Mouse(..) => *role == Turn::MousePlayer,
+                GameMove::Wall(..) => *role == Turn::TrapperPlayer,
+            })
+            .count()
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct PlayerStats {
+    pub username: String,
+    pub games_played: u32,
+    pub mouse_wins: u32,
+    pub mouse_losses: u32,
+    pub trapper_wins: u32,
+    pub trapper_losses: u32,
+    /// Walls placed per game the player won as trapper by trapping the mouse.
+    pub average_moves_to_trap: Option<f64>,
+    /// Mouse moves per game the player won as mouse by reaching the edge.
+    pub average_escape_length: Option<f64>,
+    pub forfeits: u32,
+    pub disconnects: u32,
+}
+
+/// One line of a player's match history, seen from that player's side.
+#[derive(Serialize, Deserialize)]
+pub struct MatchSummary {
+    pub match_id: u32,
+    pub role: Turn,
+    pub opponent: String,
+    pub won: Option<bool>,
+    pub ending: GameEnding,
+    pub rated: bool,
+    pub moves: usize,
+    pub ended_at: u64,
+}
+
+/// `matches.jsonl` rotates to `matches.1.jsonl`.
+fn rotated_path(path: &Path) -> PathBuf {
+    path.with_extension("1.jsonl")
+}
+
+/// Adds the records in the file to `matches`, keeping the latest
+/// `MAX_MATCHES`, and returns how many the file held. A line cut short by a
+/// crash is skipped.
+fn read_records(path: &Path, matches: &mut VecDeque<MatchRecord>) -> std::io::Result<usize> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut count = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        count += 1;
+        match serde_json::from_str(&line) {
+            Ok(record) => matches.push_back(record),
+            Err(e) => error!(error = %e, "skipping a damaged match record"),
+        }
+        if matches.len() > MAX_MATCHES {
+            matches.pop_front();
+        }
+    }
+    Ok(count)
+}
+
+/// Writes `records` to a fresh file, one per line.
+fn write_records(path: &Path, records: &[MatchRecord]) -> std::io::Result<()> {
+    let mut contents = String::new();
+    for record in records {
+        contents.push_str(&serde_json::to_string(record)?);
+        contents.push('\n');
+    }
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)
+}
+
+fn average(values: &[usize]) -> Option<f64> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.iter().sum::<usize>() as f64 / values.len() as f64)
+    }
+}
+
+/// Games kept in memory, for stats, history and replays. The file holds as
+/// many before it is rotated, so at most twice this is on disk.
+const MAX_MATCHES: usize = 10_000;
+
+/// Finished multiplayer games: the latest `MAX_MATCHES` in memory, and on
+/// disk one JSON record per line, appended after every game. A full file
+/// is rotated to `<name>.1.jsonl`, replacing the one rotated before it.
+pub struct MatchHistory {
+    path: PathBuf,
+    matches: VecDeque<MatchRecord>,
+    /// Records in the current file.
+    in_file: usize,
+    next_match_id: u32,
+}
+
+impl MatchHistory {
+    /// Reads the rotated file and the current one. A history still in the
+    /// single JSON array of older versions is moved over to the new format.
+    pub fn load(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let legacy = path.with_extension("json");
+        if !path.exists() && legacy.exists() {
+            let records: Vec<MatchRecord> = serde_json::from_str(&fs::read_to_string(&legacy)?)?;
+            write_records(&path, &records)?;
+            fs::rename(&legacy, legacy.with_extension("json.migrated"))?;
+            info!(
+                matches = records.len(),
+                path = %path.display(),
+                "match history moved to one record per line"
+            );
+        }
+
+        let mut matches = VecDeque::new();
+        read_records(&rotated_path(&path), &mut matches)?;
+        let in_file = read_records(&path, &mut matches)?;
+        let next_match_id = matches
+            .iter()
+            .map(|record| record.match_id)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        Ok(Self {
+            path,
+            matches,
+            in_file,
+            next_match_id,
+        })
+    }
+
+    fn append(&mut self, record: &MatchRecord) -> std::io::Result<()> {
+        if self.in_file >= MAX_MATCHES {
+            fs::rename(&self.path, rotated_path(&self.path))?;
+            self.in_file = 0;
+        }
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        self.in_file += 1;
+        Ok(())
+    }
+
+    pub fn add(&mut self, mut record: MatchRecord) -> u32 {
+        record.match_id = self.next_match_id;
+        self.next_match_id += 1;
+        if let Err(e) = self.append(&record) {
+            error!(error = %e, "could not save match history");
+        }
+        let match_id = record.match_id;
+        self.matches.push_back(record);
+        if self.matches.len() > MAX_MATCHES {
+            self.matches.pop_front();
+        }
+        match_id
+    }
+
+    pub fn find(&self, match_id: u32) -> Option<&MatchRecord> {
+        self.matches
+            .iter()
+            .find(|record| record.match_id == match_id)
+    }
+
+    /// The player's most recent games first.
+    pub fn history(&self, username: &str, limit: usize) -> Vec<MatchSummary> {
+        self.matches
+            .iter()
+            .rev()
+            .filter_map(|record| {
+                let role = record.role_of(username)?;
+                let opponent = match role {
+                    Turn::MousePlayer => record.trapper_player.clone(),
+                    Turn::TrapperPlayer => record.mouse_player.clone(),
+                };
+                Some(MatchSummary {
+                    match_id: record.match_id,
+                    won: record.winner.as_ref().map(|winner| *winner == role),
+                    role,
+                    opponent,
+                    ending: record.ending.clone(),
+                    rated: record.rated,
+                    moves: record.moves.len(),
+                    ended_at: record.ended_at,
+                })
+            })
+            .take(limit)
+            .collect()
+    }
+
+    pub fn stats(&self, username: &str) -> PlayerStats {
+        let mut stats = PlayerStats {
+            username: username.to_string(),
+            ..PlayerStats::default()
+        };
+        let mut moves_to_trap = Vec::new();
+        let mut escape_lengths = Vec::new();
+
+        for record in &self.matches {
+            let Some(role) = record.role_of(username) else {
+                continue;
+            };
+            stats.games_played += 1;
+            match (&role, &record.winner) {
+                (Turn::MousePlayer, Some(Turn::MousePlayer)) => stats.mouse_wins += 1,
+                (Turn::MousePlayer, Some(Turn::TrapperPlayer)) => stats.mouse_losses += 1,
+                (Turn::TrapperPlayer, Some(Turn::TrapperPlayer)) => stats.trapper_wins += 1,
+                (Turn::TrapperPlayer, Some(Turn::MousePlayer)) => stats.trapper_losses += 1,
+                (_, None) => {}
+            }
+            match &record.ending {
+                GameEnding::Forfeit(player) if player == username => stats.forfeits += 1,
+                GameEnding::Disconnect(player) if player == username => stats.disconnects += 1,
+                GameEnding::Finished if record.winner.as_ref() == Some(&role) => {
+                    match role {
+                        Turn::TrapperPlayer => moves_to_trap.push(record.count_moves(&role)),
+                        Turn::MousePlayer => escape_lengths.push(record.count_moves(&role)),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        stats.average_moves_to_trap = average(&moves_to_trap);
+        stats.average_escape_length = average(&escape_lengths);
+        stats
+    }
+}
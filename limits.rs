@@ -0,0 +1,87 @@
+//! Protection against clients that flood the server: token buckets for
+//! command rates, and the strikes that close a connection which keeps going
+//! over its limits.
+
+use std::time::{Duration, Instant};
+
+/// Strikes older than this are forgotten.
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Lets `per_second` commands through on average, and up to `burst` at once
+/// after a quiet spell. A rate of 0 lets everything through.
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        let burst = f64::from(burst.max(per_second).max(1));
+        Self {
+            per_second: f64::from(per_second),
+            burst,
+            tokens: burst,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes a token if there is one.
+    pub fn allow(&mut self) -> bool {
+        if self.per_second == 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.burst);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Counts limit violations over the last minute. With a maximum of 0 no
+/// connection is ever closed for them.
+pub struct Strikes {
+    max: usize,
+    times: Vec<Instant>,
+}
+
+impl Strikes {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            times: Vec::new(),
+        }
+    }
+
+    /// Records a violation; true once there have been too many.
+    pub fn add(&mut self) -> bool {
+        self.times.push(Instant::now());
+        self.exceeded()
+    }
+
+    /// Whether there have been too many violations in the last minute,
+    /// without recording another.
+    pub fn exceeded(&mut self) -> bool {
+        self.forget_old();
+        self.max > 0 && self.times.len() >= self.max
+    }
+
+    /// Whether every violation has been forgotten.
+    pub fn is_clear(&mut self) -> bool {
+        self.forget_old();
+        self.times.is_empty()
+    }
+
+    fn forget_old(&mut self) {
+        let now = Instant::now();
+        self.times
+            .retain(|time| now.duration_since(*time) < STRIKE_WINDOW);
+    }
+}
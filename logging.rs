@@ -0,0 +1,89 @@
+//! Log setup shared by the server and the client. Both log through `tracing`;
+//! this picks the level and the output format.
+
+use serde::Deserialize;
+use tracing::level_filters::LevelFilter;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(format!("unknown log level '{}'", name)),
+        }
+    }
+
+    fn filter(self) -> LevelFilter {
+        match self {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// Text is for people reading a terminal, JSON (one object per line) for
+/// log collectors.
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}'", name)),
+        }
+    }
+}
+
+/// Sends log output to stdout. Events carry the fields of the spans they
+/// happen in, such as the connection and the room.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn init(level: LogLevel, format: LogFormat) {
+    use std::io::IsTerminal;
+
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level.filter())
+        .with_target(false)
+        .with_ansi(std::io::stdout().is_terminal());
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("could not set up logging: {}", e);
+    }
+}
+
+/// In the browser everything goes to the developer console, always as text.
+#[cfg(target_arch = "wasm32")]
+pub fn init(level: LogLevel, _format: LogFormat) {
+    let config = tracing_wasm::WASMLayerConfigBuilder::new()
+        .set_max_level(level.filter().into_level().unwrap_or(tracing::Level::ERROR))
+        .build();
+    tracing_wasm::set_as_global_default_with_config(config);
+}
@@ -0,0 +1,469 @@
+//! Counters for the Prometheus exporter, and the HTTP endpoint that serves
+//! them. Counters and histograms are process wide and lock free, so anything
+//! can record into them; gauges such as connected clients are read from the
+//! server when a scrape comes in.
+
+use std::fmt::Write as _;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{
+    Arc, LockResult, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info_span, Instrument};
+
+use crate::game::{GameState, RoomType, Turn};
+use crate::Server;
+
+/// Commands counted by name; anything else is counted as "unknown".
+const COMMANDS: &[&str] = &[
+    "hello",
+    "register",
+    "login",
+    "logout",
+    "get_update",
+    "chat",
+    "lobby_chat",
+    "create_room",
+    "create_single_room",
+    "set_difficulty",
+    "join_room",
+    "exit_room",
+    "after_exit_room",
+    "ready",
+    "move_mouse",
+    "place_trap",
+    "AI",
+    "AI_Move",
+    "game_over",
+    "rematch",
+    "join_queue",
+    "leave_queue",
+    "delete_room",
+    "delete_room_by_name",
+    "leaderboard",
+    "stats",
+    "history",
+    "replay",
+    "unknown",
+];
+
+/// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 7] = [0.000_001, 0.000_01, 0.000_1, 0.001, 0.01, 0.1, 1.0];
+
+/// The locks whose hold times are measured.
+#[derive(Clone, Copy)]
+pub enum LockName {
+    Lobby,
+    Rooms,
+    Room,
+    Accounts,
+    History,
+    Persistence,
+}
+
+const LOCK_NAMES: [&str; 6] = [
+    "lobby",
+    "rooms",
+    "room",
+    "accounts",
+    "history",
+    "persistence",
+];
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            sum_nanos: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Writes the series for one label set, e.g. `lock="room"`, or none.
+    fn render(&self, out: &mut String, name: &str, labels: Option<&str>) {
+        let with_le = |le: &str| match labels {
+            Some(labels) => format!("{{{},le=\"{}\"}}", labels, le),
+            None => format!("{{le=\"{}\"}}", le),
+        };
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                name,
+                with_le(&bound.to_string()),
+                cumulative
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let labels = labels
+            .map(|labels| format!("{{{}}}", labels))
+            .unwrap_or_default();
+        let _ = writeln!(out, "{}_bucket{} {}", name, with_le("+Inf"), count);
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, count);
+    }
+}
+
+pub struct Metrics {
+    commands: [AtomicU64; COMMANDS.len()],
+    rejected: [AtomicU64; COMMANDS.len()],
+    /// Won by the mouse, won by the trapper, ended without a winner.
+    games_finished: [AtomicU64; 3],
+    ai_move: Histogram,
+    lock_hold: [Histogram; LOCK_NAMES.len()],
+}
+
+pub static METRICS: Metrics = Metrics {
+    commands: [const { AtomicU64::new(0) }; COMMANDS.len()],
+    rejected: [const { AtomicU64::new(0) }; COMMANDS.len()],
+    games_finished: [const { AtomicU64::new(0) }; 3],
+    ai_move: Histogram::new(),
+    lock_hold: [const { Histogram::new() }; LOCK_NAMES.len()],
+};
+
+/// The command's name as counted: its first word if the server knows it.
+pub fn command_name(message: &str) -> &'static str {
+    let first = message.split_whitespace().next().unwrap_or("");
+    COMMANDS
+        .iter()
+        .find(|command| **command == first)
+        .copied()
+        .unwrap_or("unknown")
+}
+
+fn command_index(command: &str) -> usize {
+    COMMANDS
+        .iter()
+        .position(|name| *name == command)
+        .unwrap_or(COMMANDS.len() - 1)
+}
+
+impl Metrics {
+    pub fn command(&self, command: &str) {
+        self.commands[command_index(command)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self, command: &str) {
+        self.rejected[command_index(command)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn game_finished(&self, winner: Option<&Turn>) {
+        let index = match winner {
+            Some(Turn::MousePlayer) => 0,
+            Some(Turn::TrapperPlayer) => 1,
+            None => 2,
+        };
+        self.games_finished[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ai_move(&self, duration: Duration) {
+        self.ai_move.observe(duration);
+    }
+
+    fn lock_held(&self, lock: LockName, duration: Duration) {
+        self.lock_hold[lock as usize].observe(duration);
+    }
+}
+
+/// A lock guard that records how long it was held when it is dropped.
+pub struct Timed<G> {
+    guard: G,
+    lock: LockName,
+    since: Instant,
+}
+
+impl<G> Timed<G> {
+    fn wrap(result: LockResult<G>, lock: LockName) -> LockResult<Self> {
+        let timed = |guard| Self {
+            guard,
+            lock,
+            since: Instant::now(),
+        };
+        match result {
+            Ok(guard) => Ok(timed(guard)),
+            Err(e) => Err(PoisonError::new(timed(e.into_inner()))),
+        }
+    }
+}
+
+impl<G: Deref> Deref for Timed<G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<G: DerefMut> DerefMut for Timed<G> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<G> Drop for Timed<G> {
+    fn drop(&mut self) {
+        METRICS.lock_held(self.lock, self.since.elapsed());
+    }
+}
+
+/// A `Mutex` whose hold times show up in the metrics.
+pub struct TimedMutex<T> {
+    inner: Mutex<T>,
+    lock: LockName,
+}
+
+impl<T> TimedMutex<T> {
+    pub fn new(lock: LockName, value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+            lock,
+        }
+    }
+
+    pub fn lock(&self) -> LockResult<Timed<MutexGuard<'_, T>>> {
+        Timed::wrap(self.inner.lock(), self.lock)
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+}
+
+/// An `RwLock` whose hold times show up in the metrics.
+pub struct TimedRwLock<T> {
+    inner: RwLock<T>,
+    lock: LockName,
+}
+
+impl<T> TimedRwLock<T> {
+    pub fn new(lock: LockName, value: T) -> Self {
+        Self {
+            inner: RwLock::new(value),
+            lock,
+        }
+    }
+
+    pub fn read(&self) -> LockResult<Timed<RwLockReadGuard<'_, T>>> {
+        Timed::wrap(self.inner.read(), self.lock)
+    }
+
+    pub fn write(&self) -> LockResult<Timed<RwLockWriteGuard<'_, T>>> {
+        Timed::wrap(self.inner.write(), self.lock)
+    }
+
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+impl Server {
+    /// Everything in the Prometheus text format.
+    fn render_metrics(&self) -> String {
+        let mut out = String::new();
+
+        let (connections, logged_in) = match self.lobby.lock() {
+            Ok(lobby) => (
+                lobby.sessions.len(),
+                lobby
+                    .sessions
+                    .iter()
+                    .filter(|session| session.username.is_some())
+                    .count(),
+            ),
+            Err(_) => (0, 0),
+        };
+        header(
+            &mut out,
+            "trapthemouse_connected_clients",
+            "gauge",
+            "Open client connections.",
+        );
+        let _ = writeln!(out, "trapthemouse_connected_clients {}", connections);
+        header(
+            &mut out,
+            "trapthemouse_logged_in_clients",
+            "gauge",
+            "Connections with a logged in user.",
+        );
+        let _ = writeln!(out, "trapthemouse_logged_in_clients {}", logged_in);
+
+        let mut rooms = [[0usize; 3]; 2];
+        for room in self.all_rooms() {
+            let kind = match room.room_type {
+                RoomType::SinglePlayer => 0,
+                RoomType::MultiPlayer => 1,
+            };
+            let state = match room.game_state {
+                GameState::Waiting => 0,
+                GameState::InGame => 1,
+                GameState::GameOver => 2,
+            };
+            rooms[kind][state] += 1;
+        }
+        header(
+            &mut out,
+            "trapthemouse_rooms",
+            "gauge",
+            "Open rooms by type and game state.",
+        );
+        for (kind, states) in ["single", "multi"].iter().zip(rooms) {
+            for (state, count) in ["waiting", "in_game", "game_over"].iter().zip(states) {
+                let _ = writeln!(
+                    out,
+                    "trapthemouse_rooms{{type=\"{}\",state=\"{}\"}} {}",
+                    kind, state, count
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "trapthemouse_commands_total",
+            "counter",
+            "Commands received, by command.",
+        );
+        for (command, count) in COMMANDS.iter().zip(&METRICS.commands) {
+            let _ = writeln!(
+                out,
+                "trapthemouse_commands_total{{command=\"{}\"}} {}",
+                command,
+                count.load(Ordering::Relaxed)
+            );
+        }
+        header(
+            &mut out,
+            "trapthemouse_commands_rejected_total",
+            "counter",
+            "Commands the server refused, by command.",
+        );
+        for (command, count) in COMMANDS.iter().zip(&METRICS.rejected) {
+            let _ = writeln!(
+                out,
+                "trapthemouse_commands_rejected_total{{command=\"{}\"}} {}",
+                command,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut out,
+            "trapthemouse_games_finished_total",
+            "counter",
+            "Games that ended, by the role that won.",
+        );
+        for (winner, count) in ["mouse", "trapper", "none"]
+            .iter()
+            .zip(&METRICS.games_finished)
+        {
+            let _ = writeln!(
+                out,
+                "trapthemouse_games_finished_total{{winner=\"{}\"}} {}",
+                winner,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut out,
+            "trapthemouse_ai_move_seconds",
+            "histogram",
+            "Time the AI takes to move the mouse.",
+        );
+        METRICS
+            .ai_move
+            .render(&mut out, "trapthemouse_ai_move_seconds", None);
+
+        header(
+            &mut out,
+            "trapthemouse_lock_hold_seconds",
+            "histogram",
+            "How long the server's locks are held, by lock.",
+        );
+        for (name, histogram) in LOCK_NAMES.iter().zip(&METRICS.lock_hold) {
+            histogram.render(
+                &mut out,
+                "trapthemouse_lock_hold_seconds",
+                Some(&format!("lock=\"{}\"", name)),
+            );
+        }
+        out
+    }
+}
+
+/// Answers `GET /metrics`; this is all the HTTP a scraper needs.
+async fn handle_scrape(mut stream: TcpStream, server: Arc<Server>) {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buffer)).await;
+        match read {
+            Ok(Ok(n)) if n > 0 && request.len() + n <= 8192 => {
+                request.extend_from_slice(&buffer[..n])
+            }
+            _ => return,
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", server.render_metrics()),
+        (Some("GET"), _) => ("404 Not Found", "not found, try /metrics\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "only GET is supported\n".to_string(),
+        ),
+    };
+    debug!(status, "metrics scrape");
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Accepts scrapes, each on its own task.
+pub async fn serve(listener: TcpListener, server: Arc<Server>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let span = info_span!("metrics", %peer);
+                tokio::spawn(handle_scrape(stream, Arc::clone(&server)).instrument(span));
+            }
+            Err(e) => {
+                error!(error = %e, "metrics accept failed");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use tracing::info;
+
+use crate::game::{ChatMessage, Room};
+
+/// The part of the server state that survives a restart. Sessions, presence
+/// and the matchmaking queue belong to live connections and are not kept.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Snapshot {
+    pub seq: u64,
+    pub rooms: Vec<Room>,
+    pub room_codes: HashMap<u32, String>,
+    #[serde(default)]
+    pub room_owners: HashMap<u32, String>,
+    pub lobby_chat: Vec<ChatMessage>,
+}
+
+/// One command and the state it produced. Rooms are stored whole rather than
+/// re-running the command, because creating rooms, AI moves and rematches are
+/// random and would not come out the same a second time.
+#[derive(Serialize, Deserialize)]
+struct WalEntry {
+    seq: u64,
+    command: String,
+    rooms: Vec<Room>,
+    removed_rooms: Vec<u32>,
+    room_codes: Option<HashMap<u32, String>>,
+    #[serde(default)]
+    room_owners: Option<HashMap<u32, String>>,
+    lobby_chat: Option<Vec<ChatMessage>>,
+}
+
+impl Snapshot {
+    fn apply(&mut self, entry: WalEntry) {
+        self.seq = entry.seq;
+        self.rooms
+            .retain(|room| !entry.removed_rooms.contains(&room.room_id));
+        for room in entry.rooms {
+            match self.rooms.iter_mut().find(|old| old.room_id == room.room_id) {
+                Some(old) => *old = room,
+                None => self.rooms.push(room),
+            }
+        }
+        if let Some(room_codes) = entry.room_codes {
+            self.room_codes = room_codes;
+        }
+        if let Some(room_owners) = entry.room_owners {
+            self.room_owners = room_owners;
+        }
+        if let Some(lobby_chat) = entry.lobby_chat {
+            self.lobby_chat = lobby_chat;
+        }
+    }
+}
+
+/// Periodic snapshots plus a write-ahead log of every command that changed
+/// the state since the last snapshot. Each log entry is synced to disk before
+/// the command is answered, so a command a client saw succeed survives a
+/// crash; a crash mid-write loses only that one unanswered command.
+pub struct Persistence {
+    snapshot_path: PathBuf,
+    wal_path: PathBuf,
+    wal: File,
+    seq: u64,
+}
+
+impl Persistence {
+    /// Loads the last snapshot and replays the log on top of it. The
+    /// recovered state is written out as a fresh snapshot straight away.
+    pub fn open(
+        snapshot_path: impl Into<PathBuf>,
+        wal_path: impl Into<PathBuf>,
+    ) -> std::io::Result<(Self, Snapshot)> {
+        let snapshot_path = snapshot_path.into();
+        let wal_path = wal_path.into();
+
+        let mut state: Snapshot = match fs::read_to_string(&snapshot_path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(e),
+        };
+
+        let mut replayed = 0;
+        if let Ok(wal) = File::open(&wal_path) {
+            for line in BufReader::new(wal).lines() {
+                // A torn last line from a crash mid-write ends the replay.
+                let Ok(entry) = serde_json::from_str::<WalEntry>(&line?) else {
+                    break;
+                };
+                if entry.seq > state.seq {
+                    state.apply(entry);
+                    replayed += 1;
+                }
+            }
+        }
+        info!(
+            rooms = state.rooms.len(),
+            snapshot = %snapshot_path.display(),
+            replayed,
+            "recovered rooms"
+        );
+
+        let wal = OpenOptions::new().create(true).append(true).open(&wal_path)?;
+        let mut persistence = Self {
+            snapshot_path,
+            wal_path,
+            wal,
+            seq: state.seq,
+        };
+        persistence.snapshot(
+            &state.rooms,
+            &state.room_codes,
+            &state.room_owners,
+            &state.lobby_chat,
+        )?;
+        Ok((persistence, state))
+    }
+
+    /// Appends a log entry for `command` with the rooms it changed or
+    /// removed, and the room codes, room owners or lobby chat if it changed
+    /// those.
+    pub fn log(
+        &mut self,
+        command: &str,
+        rooms: Vec<Room>,
+        removed_rooms: Vec<u32>,
+        room_codes: Option<HashMap<u32, String>>,
+        room_owners: Option<HashMap<u32, String>>,
+        lobby_chat: Option<Vec<ChatMessage>>,
+    ) -> std::io::Result<()> {
+        let entry = WalEntry {
+            seq: self.seq + 1,
+            command: command.to_string(),
+            rooms,
+            removed_rooms,
+            room_codes,
+            room_owners,
+            lobby_chat,
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        self.wal.write_all(line.as_bytes())?;
+        self.wal.sync_data()?;
+        self.seq = entry.seq;
+        Ok(())
+    }
+
+    /// Writes the whole state to the snapshot file and starts a new log.
+    pub fn snapshot(
+        &mut self,
+        rooms: &[Room],
+        room_codes: &HashMap<u32, String>,
+        room_owners: &HashMap<u32, String>,
+        lobby_chat: &[ChatMessage],
+    ) -> std::io::Result<()> {
+        let snapshot = Snapshot {
+            seq: self.seq,
+            rooms: rooms.to_vec(),
+            room_codes: room_codes.clone(),
+            room_owners: room_owners.clone(),
+            lobby_chat: lobby_chat.to_vec(),
+        };
+        let tmp_path = self.snapshot_path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(serde_json::to_string(&snapshot)?.as_bytes())?;
+        // The snapshot has to be on disk before the log it replaces is cut.
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.snapshot_path)?;
+
+        // Entries up to `seq` are in the snapshot now, so the log can start over.
+        self.wal = File::create(&self.wal_path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+//! Versioning of the text protocol shared by the server, the clients and the
+//! tournament runner.
+//!
+//! A connection starts with `hello <major>.<minor> <capabilities>`, which the
+//! server answers with `OK:hello <major>.<minor> <capabilities>` or, when the
+//! major versions differ, an `ERR:` line before closing the connection.
+//! Capabilities are comma separated names, or `-` for none.
+//!
+//! Minor versions only add things: new commands, and new JSON fields that
+//! older peers ignore and newer peers default when they are missing. Anything
+//! else bumps the major version.
+//!
+//! Since 1.2 any command, even one that is normally not answered, can get
+//! `ERR:limit <command> <reason>` back when it goes over one of the server's
+//! limits, so clients can tell which command was refused.
+//!
+//! Since 1.3 get_update has `shutdown_in`, the seconds left, while the
+//! server counts down to a shutdown. Then every connection gets
+//! `ERR:server shutting down` and is closed.
+//!
+//! Since 2.0 every command sent over TCP ends with a newline, and the server
+//! reads them line by line; 1.x clients relied on each command arriving in a
+//! read of its own. Over WebSocket each text message is still one command.
+//!
+//! Since 2.1 a client that sends the `replies` capability hears why the
+//! server refused a command that is not answered when it goes through:
+//! `ERR:<command> <reason>`, for the commands in `UNANSWERED`. Such a
+//! client is also told the id of its new single player room, `OK:<room id>`.
+
+pub const PROTOCOL_MAJOR: u32 = 2;
+pub const PROTOCOL_MINOR: u32 = 1;
+
+pub const LIMIT_ERROR: &str = "ERR:limit ";
+pub const SHUTDOWN_NOTICE: &str = "ERR:server shutting down";
+
+/// Commands that get no answer when they go through.
+pub const UNANSWERED: &[&str] = &[
+    "chat",
+    "lobby_chat",
+    "set_difficulty",
+    "join_room",
+    "exit_room",
+    "after_exit_room",
+    "ready",
+    "move_mouse",
+    "place_trap",
+    "AI",
+    "AI_Move",
+    "game_over",
+    "rematch",
+    "join_queue",
+    "create_room",
+    "delete_room",
+    "delete_room_by_name",
+];
+
+/// The reply line for a command refused by a limit.
+pub fn limit_error(command: &str, reason: &str) -> String {
+    format!("{}{} {}\n", LIMIT_ERROR, command, reason)
+}
+
+/// Splits a limit error line into the refused command and the reason.
+pub fn parse_limit_error(line: &str) -> Option<(&str, &str)> {
+    line.trim().strip_prefix(LIMIT_ERROR)?.split_once(' ')
+}
+
+/// The reply line for an unanswered command the server refused.
+pub fn refusal(command: &str, reason: &str) -> String {
+    format!("ERR:{} {}\n", command, reason)
+}
+
+/// Splits a refusal line into the refused command and the reason.
+pub fn parse_refusal(line: &str) -> Option<(&str, &str)> {
+    let (command, reason) = line.trim().strip_prefix("ERR:")?.split_once(' ')?;
+    UNANSWERED.contains(&command).then_some((command, reason))
+}
+
+pub struct Hello {
+    pub major: u32,
+    pub minor: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    pub fn new(capabilities: &[&str]) -> Self {
+        Self {
+            major: PROTOCOL_MAJOR,
+            minor: PROTOCOL_MINOR,
+            capabilities: capabilities.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    /// Reads `hello 1.0 a,b`, as sent by a client or after the server's `OK:`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parts = text.split_whitespace();
+        if parts.next()? != "hello" {
+            return None;
+        }
+        let (major, minor) = parts.next()?.split_once('.')?;
+        let capabilities = match parts.next() {
+            None | Some("-") => Vec::new(),
+            Some(names) => names.split(',').map(|name| name.to_string()).collect(),
+        };
+        Some(Self {
+            major: major.parse().ok()?,
+            minor: minor.parse().ok()?,
+            capabilities,
+        })
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.major == PROTOCOL_MAJOR
+    }
+
+    pub fn version(&self) -> String {
+        format!("{}.{}", self.major, self.minor)
+    }
+
+    pub fn has(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|name| name == capability)
+    }
+
+    /// The hello command itself, with the trailing space every command has.
+    /// The transport adds the newline.
+    pub fn to_command(&self) -> String {
+        let capabilities = if self.capabilities.is_empty() {
+            "-".to_string()
+        } else {
+            self.capabilities.join(",")
+        };
+        format!("hello {} {} ", self.version(), capabilities)
+    }
+}
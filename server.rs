@@ -1,396 +1,2734 @@
-use rand::prelude::SliceRandom;
-use rand::Rng;
+mod accounts;
+mod admin;
+mod config;
+#[allow(dead_code)]
+mod game;
+mod history;
+mod limits;
+mod logging;
+mod metrics;
+mod persistence;
+#[allow(dead_code)]
+mod protocol;
+mod shutdown;
+mod tls;
+#[cfg(test)]
+mod tls_client;
+
+use accounts::{hash_password, validate_username, verify_password, AccountStore};
+use config::{Config, LimitsConfig};
+use history::{GameEnding, MatchHistory, MatchRecord};
+use limits::{RateLimiter, Strikes};
+use metrics::{command_name, LockName, TimedMutex, TimedRwLock, METRICS};
+use persistence::{Persistence, Snapshot};
+use protocol::{limit_error, refusal, Hello, PROTOCOL_MAJOR, SHUTDOWN_NOTICE, UNANSWERED};
+use game::{
+    unix_now, BoardConfig, ChatMessage, Difficulty, GameState, Room, RoomType, Turn,
+    MAX_CHAT_HISTORY, MAX_CHAT_LENGTH, MAX_INITIAL_WALLS,
+};
+use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 use std::thread;
-#[derive(Serialize, Deserialize, PartialEq)]
-enum GameState {
-    Waiting,
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{watch, Notify, Semaphore};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, error, info, info_span, trace, warn, Instrument, Span};
+
+const CHAT_BURST: usize = 5;
+const CHAT_WINDOW: Duration = Duration::from_secs(10);
+const MAX_ROOM_CODE_LENGTH: usize = 32;
+const IDLE_ERROR: &str = "ERR:no commands for too long, disconnecting\n";
+/// Sent back in the hello reply so clients can hide what this server lacks.
+const CAPABILITIES: &[&str] = &[
+    "chat",
+    "lobby_chat",
+    "matchmaking",
+    "private_rooms",
+    "ratings",
+    "history",
+    "replay",
+    "announcements",
+    "shutdown",
+    "replies",
+];
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+enum PresenceStatus {
+    Idle,
+    InLobby,
     InGame,
-    GameOver,
-}
-
-#[derive(Serialize, Deserialize, PartialEq)]
-enum Turn {
-    MousePlayer,
-    TrapperPlayer,
-}
-#[derive(Serialize, Deserialize)]
-
-enum RoomType {
-    SinglePlayer,
-    MultiPlayer,
-}
-#[derive(Serialize, Deserialize, PartialEq)]
-
-enum Difficulty {
-    Easy,
-    Medium,
-    Hard,
-}
-
-#[derive(Serialize, Deserialize)]
-struct Room {
-    room_id: u32,
-    room_name: String,
-    mouse_player: Option<String>,
-    trapper_player: Option<String>,
-    game_state: GameState,
-    mouse_position: (u32, u32),
-    walls: Vec<(u32, u32)>,
-    turn: Turn,
-    winner: Option<Turn>,
-    room_type: RoomType,
-    game_difficulty: Option<Difficulty>,
-    mouse_player_exited: bool,
-    trapper_player_exited: bool,
-}
-
-impl Room {
-    pub fn new(room_id: u32, room_name: String) -> Self {
-        let mut rng = rand::thread_rng();
-
-        let walls = {
-            let mut rand_walls = Vec::new();
-            while rand_walls.len() < 6 {
-                let x = rng.gen_range(0..11);
-                let y = rng.gen_range(0..11);
-                if (x, y) != (5, 5) && !rand_walls.contains(&(x, y)) {
-                    rand_walls.push((x, y));
-                }
+    Spectating,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Presence {
+    username: String,
+    status: PresenceStatus,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+enum QueueRole {
+    Mouse,
+    Trapper,
+    Any,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct QueueEntry {
+    username: String,
+    role: QueueRole,
+    board: BoardConfig,
+    queued_at: u64,
+}
+
+struct Session {
+    connection_id: u32,
+    username: Option<String>,
+    /// Notified to close the connection, e.g. when an admin kicks the user.
+    kick: Arc<Notify>,
+}
+
+/// A message from the operators, shown to everyone until it is replaced.
+#[derive(Serialize, Clone)]
+struct Announcement {
+    text: String,
+    sent_at: u64,
+}
+
+/// Everything outside the rooms: sessions, the quick match queue, the lobby
+/// chat, the codes of private rooms and who created which room.
+#[derive(Default)]
+struct Lobby {
+    lobby_chat: Vec<ChatMessage>,
+    queue: Vec<QueueEntry>,
+    sessions: Vec<Session>,
+    room_codes: HashMap<u32, String>,
+    announcement: Option<Announcement>,
+    /// Command rates of logged in users, across all their connections.
+    user_limits: HashMap<String, RateLimiter>,
+    /// Who created which room. Counts toward `rooms_per_user`, and the owner
+    /// of a single player room is the one who plays it.
+    room_owners: HashMap<u32, String>,
+    /// When users whose last connection closed left. Their seats are kept
+    /// for the reconnect grace in case they log in again.
+    departures: HashMap<String, Instant>,
+    /// Recent failed logins from each address.
+    failed_logins: HashMap<IpAddr, Strikes>,
+    /// When the server closes every connection, once it is shutting down.
+    shutdown_at: Option<u64>,
+}
+
+impl Lobby {
+    pub fn leave_queue(&mut self, username: &str) {
+        self.queue.retain(|entry| entry.username != username);
+    }
+
+    pub fn prune_queue(&mut self, timeout: u64) {
+        let now = unix_now();
+        self.queue
+            .retain(|entry| now.saturating_sub(entry.queued_at) < timeout);
+    }
+
+    pub fn push_lobby_chat(&mut self, username: &str, text: &str) {
+        self.lobby_chat.push(ChatMessage {
+            username: username.to_string(),
+            text: text.to_string(),
+        });
+        if self.lobby_chat.len() > MAX_CHAT_HISTORY {
+            let excess = self.lobby_chat.len() - MAX_CHAT_HISTORY;
+            self.lobby_chat.drain(..excess);
+        }
+    }
+
+    /// Whether a room owner or player is around: a user who is connected or
+    /// still within the reconnect grace, or an anonymous `#<connection>`
+    /// whose connection is open.
+    fn is_present(&self, name: &str) -> bool {
+        match name.strip_prefix('#') {
+            Some(connection_id) => self
+                .sessions
+                .iter()
+                .any(|session| session.connection_id.to_string() == connection_id),
+            None => {
+                self.departures.contains_key(name)
+                    || self
+                        .sessions
+                        .iter()
+                        .any(|session| session.username.as_deref() == Some(name))
             }
-            rand_walls
-        };
+        }
+    }
+
+    pub fn code_matches(&self, room_id: u32, code: Option<&str>) -> bool {
+        match self.room_codes.get(&room_id) {
+            Some(expected) => code == Some(expected.as_str()),
+            None => true,
+        }
+    }
+}
+
+/// The reply to get_update.
+#[derive(Serialize)]
+struct ServerView {
+    rooms: Vec<Room>,
+    lobby_chat: Vec<ChatMessage>,
+    presence: Vec<Presence>,
+    queue: Vec<QueueEntry>,
+    announcement: Option<Announcement>,
+    /// Seconds until the server shuts down, during the countdown.
+    shutdown_in: Option<u64>,
+}
+
+/// Shared by every connection task. Each room has its own lock, so traffic in
+/// one room never waits for another. Locks are always taken in the order
+/// persistence, lobby, room map, room, and never held across an await.
+struct Server {
+    rooms: TimedRwLock<BTreeMap<u32, Arc<TimedMutex<Room>>>>,
+    lobby: TimedMutex<Lobby>,
+    accounts: TimedMutex<AccountStore>,
+    history: TimedMutex<MatchHistory>,
+    persistence: TimedMutex<Option<Persistence>>,
+    next_connection_id: AtomicU32,
+    config: Config,
+    /// Set to true to close every connection for shutdown.
+    shutdown: watch::Sender<bool>,
+    /// Rooms are only swept once the players of restored games have had the
+    /// reconnect grace to come back.
+    started: Instant,
+    /// Bounds how many passwords are hashed at once, one per CPU, so logins
+    /// cannot take over the blocking pool.
+    hashing: Semaphore,
+}
 
+/// Per-connection protocol state.
+struct Client {
+    connection_id: u32,
+    /// The address the connection comes from.
+    peer: IpAddr,
+    /// The client's hello, once it has sent a compatible one.
+    hello: Option<Hello>,
+    username: Option<String>,
+    chat_times: Vec<Instant>,
+    commands: RateLimiter,
+    updates: RateLimiter,
+    strikes: Strikes,
+    /// Set when the connection should be closed after the current reply.
+    closing: bool,
+}
+
+impl Client {
+    fn new(connection_id: u32, peer: IpAddr, limits: &LimitsConfig) -> Self {
         Self {
+            connection_id,
+            peer,
+            hello: None,
+            username: None,
+            chat_times: Vec::new(),
+            commands: RateLimiter::new(limits.commands_per_second, limits.command_burst),
+            updates: RateLimiter::new(limits.updates_per_second, limits.updates_per_second),
+            strikes: Strikes::new(limits.max_violations),
+            closing: false,
+        }
+    }
+
+    /// Who a new room counts against: the user, or the connection itself
+    /// when nobody is logged in.
+    fn owner(&self) -> String {
+        match &self.username {
+            Some(username) => username.clone(),
+            None => format!("#{}", self.connection_id),
+        }
+    }
+
+    /// Whether the client asked to hear why commands that are not otherwise
+    /// answered were refused.
+    fn wants_replies(&self) -> bool {
+        self.hello
+            .as_ref()
+            .is_some_and(|hello| hello.has("replies"))
+    }
+}
+
+/// What a command changed, so that only that goes into the command log.
+#[derive(Default)]
+struct Changes {
+    rooms: Vec<u32>,
+    lobby_chat: bool,
+    room_codes: bool,
+    room_owners: bool,
+    records: Vec<MatchRecord>,
+}
+
+impl Changes {
+    fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+            && !self.lobby_chat
+            && !self.room_codes
+            && !self.room_owners
+            && self.records.is_empty()
+    }
+}
+
+impl Server {
+    pub fn new(config: Config, accounts: AccountStore, history: MatchHistory) -> Self {
+        Self {
+            rooms: TimedRwLock::new(LockName::Rooms, BTreeMap::new()),
+            lobby: TimedMutex::new(LockName::Lobby, Lobby::default()),
+            accounts: TimedMutex::new(LockName::Accounts, accounts),
+            history: TimedMutex::new(LockName::History, history),
+            persistence: TimedMutex::new(LockName::Persistence, None),
+            next_connection_id: AtomicU32::new(1),
+            config,
+            shutdown: watch::Sender::new(false),
+            started: Instant::now(),
+            hashing: Semaphore::new(thread::available_parallelism().map_or(1, |cpus| cpus.get())),
+        }
+    }
+
+    /// Puts back the rooms and lobby chat recovered from disk. Spectators are
+    /// dropped since their connections are gone; seated players keep their
+    /// seats and can pick the game up again after logging in.
+    pub fn restore(&mut self, persistence: Persistence, snapshot: Snapshot) {
+        let rooms = self.rooms.get_mut().unwrap();
+        for mut room in snapshot.rooms {
+            room.spectators.clear();
+            let room_id = room.room_id;
+            rooms.insert(room_id, Arc::new(TimedMutex::new(LockName::Room, room)));
+        }
+        let lobby = self.lobby.get_mut().unwrap();
+        lobby.room_codes = snapshot.room_codes;
+        lobby.room_owners = snapshot.room_owners;
+        lobby.lobby_chat = snapshot.lobby_chat;
+        *self.persistence.get_mut().unwrap() = Some(persistence);
+    }
+
+    fn room(&self, room_id: u32) -> Option<Arc<TimedMutex<Room>>> {
+        self.rooms.read().ok()?.get(&room_id).cloned()
+    }
+
+    fn with_room<T>(&self, room_id: u32, f: impl FnOnce(&mut Room) -> T) -> Option<T> {
+        let room = self.room(room_id)?;
+        let mut room = room.lock().ok()?;
+        Some(f(&mut room))
+    }
+
+    fn room_owner(&self, room_id: u32) -> Option<String> {
+        self.lobby.lock().ok()?.room_owners.get(&room_id).cloned()
+    }
+
+    /// Copies of all rooms, each taken under its own lock.
+    fn all_rooms(&self) -> Vec<Room> {
+        match self.rooms.read() {
+            Ok(rooms) => rooms
+                .values()
+                .filter_map(|room| room.lock().ok().map(|room| room.clone()))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Whether the user holds a seat in a game that is still to be played or
+    /// being played. Finished games and ones the user walked out of do not
+    /// count.
+    fn is_seated(&self, username: &str) -> bool {
+        match self.rooms.read() {
+            Ok(rooms) => rooms.values().any(|room| {
+                room.lock().is_ok_and(|room| {
+                    room.game_state != GameState::GameOver
+                        && ((room.mouse_player.as_deref() == Some(username)
+                            && !room.mouse_player_exited)
+                            || (room.trapper_player.as_deref() == Some(username)
+                                && !room.trapper_player_exited))
+                })
+            }),
+            Err(_) => false,
+        }
+    }
+
+    pub fn has_room_capacity(&self) -> bool {
+        self.rooms
+            .read()
+            .is_ok_and(|rooms| rooms.len() < self.config.max_rooms)
+    }
+
+    fn add_room(&self, build: impl FnOnce(u32) -> Room) -> Result<u32, String> {
+        let mut rooms = self
+            .rooms
+            .write()
+            .map_err(|_| "rooms unavailable".to_string())?;
+        if rooms.len() >= self.config.max_rooms {
+            return Err("the server has no free rooms".to_string());
+        }
+        let room_id = rooms.keys().next_back().map_or(1, |room_id| room_id + 1);
+        rooms.insert(
             room_id,
-            room_name,
-            mouse_player: None,
-            trapper_player: None,
-            game_state: GameState::Waiting,
-            mouse_position: (5, 5),
-            walls,
-            turn: Turn::TrapperPlayer,
-            winner: None,
-            room_type: RoomType::MultiPlayer,
-            game_difficulty: None,
-            mouse_player_exited: false,
-            trapper_player_exited: false,
-        }
-    }
-
-    pub fn ai_move(&mut self) {
-        let (mouse_x, mouse_y) = self.mouse_position;
-        let posib_moves = self.posib_moves(mouse_x, mouse_y);
-
-        match self.game_difficulty.as_ref() {
-            Some(Difficulty::Easy) => {
-                if let Some(&(new_x, new_y)) = posib_moves.choose(&mut rand::thread_rng()) {
-                    self.mouse_position = (new_x, new_y);
-                    self.turn = Turn::TrapperPlayer;
+            Arc::new(TimedMutex::new(LockName::Room, build(room_id))),
+        );
+        Ok(room_id)
+    }
+
+    /// Removes every room `remove` picks and returns their ids.
+    fn remove_rooms(&self, remove: impl Fn(&Room) -> bool) -> Vec<u32> {
+        let Ok(mut rooms) = self.rooms.write() else {
+            return Vec::new();
+        };
+        let removed: Vec<u32> = rooms
+            .iter()
+            .filter(|(_, room)| room.lock().is_ok_and(|room| remove(&room)))
+            .map(|(room_id, _)| *room_id)
+            .collect();
+        for room_id in &removed {
+            rooms.remove(room_id);
+        }
+        removed
+    }
+
+    /// Removes the rooms `pick` chooses that the connection may delete, and
+    /// forgets their owners and codes. Returns the ids of the rooms removed.
+    fn delete_rooms(&self, client: &Client, pick: impl Fn(&Room) -> bool) -> Vec<u32> {
+        let Ok(owners) = self.lobby.lock().map(|lobby| lobby.room_owners.clone()) else {
+            return Vec::new();
+        };
+        let removed = self.remove_rooms(|room| {
+            pick(room) && may_delete(room, client, owners.get(&room.room_id).map(String::as_str))
+        });
+        if let Ok(mut lobby) = self.lobby.lock() {
+            for room_id in &removed {
+                lobby.room_owners.remove(room_id);
+                lobby.room_codes.remove(room_id);
+            }
+        }
+        removed
+    }
+
+    /// Removes the rooms nobody is coming back to: their owner has left and
+    /// so has every player who has not walked out of the game already. That
+    /// covers empty rooms and finished games alike, so they stop counting
+    /// toward `max_rooms` and `rooms_per_user`.
+    fn sweep_rooms(&self, changes: &mut Changes) {
+        if self.started.elapsed() < self.config.reconnect_grace() {
+            return;
+        }
+        let Ok(mut lobby) = self.lobby.lock() else {
+            return;
+        };
+        let removed = self.remove_rooms(|room| {
+            let seats = [
+                (&room.mouse_player, room.mouse_player_exited),
+                (&room.trapper_player, room.trapper_player_exited),
+            ];
+            !lobby
+                .room_owners
+                .get(&room.room_id)
+                .is_some_and(|owner| lobby.is_present(owner))
+                && seats.iter().all(|(player, exited)| match player {
+                    Some(player) => *exited || !lobby.is_present(player),
+                    None => true,
+                })
+        });
+        if removed.is_empty() {
+            return;
+        }
+        info!(rooms = removed.len(), "abandoned rooms removed");
+        for room_id in &removed {
+            lobby.room_owners.remove(room_id);
+            lobby.room_codes.remove(room_id);
+        }
+        changes.rooms.extend(removed);
+        changes.room_owners = true;
+        changes.room_codes = true;
+    }
+
+    /// Registers a new connection, unless the server is already full, and
+    /// returns its id and the signal that closes it.
+    pub fn open_session(&self) -> Result<(u32, Arc<Notify>), String> {
+        let mut lobby = self
+            .lobby
+            .lock()
+            .map_err(|_| "lobby unavailable".to_string())?;
+        if lobby.sessions.len() >= self.config.max_connections {
+            warn!("connection refused, the server is full");
+            return Err("server is full, try again later".to_string());
+        }
+        let connection_id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let kick = Arc::new(Notify::new());
+        lobby.sessions.push(Session {
+            connection_id,
+            username: None,
+            kick: Arc::clone(&kick),
+        });
+        Span::current().record("connection_id", connection_id);
+        info!("connected");
+        Ok((connection_id, kick))
+    }
+
+    pub fn login_session(&self, connection_id: u32, username: &str) {
+        if let Ok(mut lobby) = self.lobby.lock() {
+            if let Some(session) = lobby
+                .sessions
+                .iter_mut()
+                .find(|session| session.connection_id == connection_id)
+            {
+                session.username = Some(username.to_string());
+            }
+            lobby.departures.remove(username);
+        }
+    }
+
+    pub fn logout_session(&self, connection_id: u32) {
+        if let Ok(mut lobby) = self.lobby.lock() {
+            let username = lobby
+                .sessions
+                .iter_mut()
+                .find(|session| session.connection_id == connection_id)
+                .and_then(|session| session.username.take());
+            if let Some(username) = username {
+                lobby.leave_queue(&username);
+            }
+        }
+    }
+
+    /// Drops the connection's session. Returns the user and when they left
+    /// if this was their last connection.
+    pub fn close_session(&self, connection_id: u32) -> Option<(String, Instant)> {
+        let mut lobby = self.lobby.lock().ok()?;
+        let username = lobby
+            .sessions
+            .iter()
+            .find(|session| session.connection_id == connection_id)
+            .and_then(|session| session.username.clone());
+        lobby
+            .sessions
+            .retain(|session| session.connection_id != connection_id);
+        let username = username?;
+        lobby.leave_queue(&username);
+        if lobby
+            .sessions
+            .iter()
+            .any(|session| session.username.as_ref() == Some(&username))
+        {
+            return None;
+        }
+        lobby.user_limits.remove(&username);
+        let departed = Instant::now();
+        lobby.departures.insert(username.clone(), departed);
+        Some((username, departed))
+    }
+
+    /// Gives up the seats of a player who left at `departed` and has not
+    /// logged in again since: their seats in rooms still waiting for a game,
+    /// and a multiplayer game in progress, which they lose by disconnect.
+    fn abandon(&self, username: &str, departed: Instant, changes: &mut Changes) {
+        {
+            let Ok(mut lobby) = self.lobby.lock() else {
+                return;
+            };
+            if lobby.departures.get(username) != Some(&departed) {
+                return;
+            }
+            lobby.departures.remove(username);
+        }
+        // Games cut short by a shutdown carry on from the snapshot after the
+        // restart instead of being lost by disconnect.
+        if *self.shutdown.borrow() {
+            return;
+        }
+
+        if let Ok(rooms) = self.rooms.read() {
+            for room in rooms.values() {
+                let Ok(mut room) = room.lock() else {
+                    continue;
+                };
+                if room.game_state != GameState::Waiting {
+                    continue;
+                }
+                if room.mouse_player.as_deref() == Some(username) {
+                    room.mouse_player = None;
+                    room.mouse_ready = false;
+                } else if room.trapper_player.as_deref() == Some(username) {
+                    room.trapper_player = None;
+                    room.trapper_ready = false;
                 } else {
-                    println!("No more moves!");
-                    self.winner = Some(Turn::TrapperPlayer);
+                    continue;
                 }
+                changes.rooms.push(room.room_id);
             }
-            Some(Difficulty::Medium) => {
-                let medium_moves: Vec<(u32, u32)> = posib_moves
-                    .iter()
-                    .filter(|&&hex| !self.danger_hex(hex))
-                    .cloned()
-                    .collect();
-
-                if !medium_moves.is_empty() {
-                    if let Some(&(new_x, new_y)) = medium_moves.choose(&mut rand::thread_rng()) {
-                        self.mouse_position = (new_x, new_y);
-                        self.turn = Turn::TrapperPlayer;
-                    } else {
-                        println!("No more moves!");
-                        self.winner = Some(Turn::TrapperPlayer);
-                    }
-                } else if let Some(&(new_x, new_y)) = posib_moves.choose(&mut rand::thread_rng()) {
-                        self.mouse_position = (new_x, new_y);
-                        self.turn = Turn::TrapperPlayer;
-                    } else {
-                        println!("No more moves!");
-                        self.winner = Some(Turn::TrapperPlayer);
-                    
+        }
+
+        let in_game = |room: &Room| {
+            room.room_type == RoomType::MultiPlayer
+                && room.game_state == GameState::InGame
+                && (room.mouse_player.as_deref() == Some(username)
+                    || room.trapper_player.as_deref() == Some(username))
+        };
+        let room = match self.rooms.read() {
+            Ok(rooms) => rooms
+                .values()
+                .find(|room| room.lock().is_ok_and(|room| in_game(&room)))
+                .cloned(),
+            Err(_) => None,
+        };
+        let Some(room) = room else {
+            return;
+        };
+        let Ok(mut room) = room.lock() else {
+            return;
+        };
+        if !in_game(&room) {
+            return;
+        }
+        let winner = if room.mouse_player.as_deref() == Some(username) {
+            room.mouse_player_exited = true;
+            Turn::TrapperPlayer
+        } else {
+            room.trapper_player_exited = true;
+            Turn::MousePlayer
+        };
+        changes.rooms.push(room.room_id);
+        changes.records.extend(finish_game(
+            &mut room,
+            Some(winner),
+            GameEnding::Disconnect(username.to_string()),
+        ));
+    }
+
+    /// Queues a player for a quick match, or pairs them straight away with
+    /// the longest waiting compatible entry. Returns the new room id on a match.
+    pub fn join_queue(
+        &self,
+        username: &str,
+        role: QueueRole,
+        board: BoardConfig,
+    ) -> Result<Option<u32>, String> {
+        let mut lobby = self
+            .lobby
+            .lock()
+            .map_err(|_| "lobby unavailable".to_string())?;
+        lobby.leave_queue(username);
+        let partner = lobby.queue.iter().position(|entry| {
+            entry.board == board
+                && match (entry.role, role) {
+                    (QueueRole::Any, _) | (_, QueueRole::Any) => true,
+                    (a, b) => a != b,
                 }
+        });
+
+        let Some(index) = partner else {
+            lobby.queue.push(QueueEntry {
+                username: username.to_string(),
+                role,
+                board,
+                queued_at: unix_now(),
+            });
+            return Ok(None);
+        };
+
+        let partner = lobby.queue.remove(index);
+        let partner_is_mouse = match (partner.role, role) {
+            (QueueRole::Mouse, _) | (_, QueueRole::Trapper) => true,
+            (QueueRole::Trapper, _) | (_, QueueRole::Mouse) => false,
+            (QueueRole::Any, QueueRole::Any) => rand::random(),
+        };
+        let (mouse, trapper) = if partner_is_mouse {
+            (partner.username.clone(), username.to_string())
+        } else {
+            (username.to_string(), partner.username.clone())
+        };
+
+        let room_id = self.add_room(|room_id| {
+            let mut room = Room::with_board(room_id, format!("match-{}", room_id), board);
+            room.mouse_player = Some(mouse);
+            room.trapper_player = Some(trapper);
+            room.mouse_ready = true;
+            room.trapper_ready = true;
+            room.rated = true;
+            room.start();
+            room
+        });
+        match room_id {
+            Ok(room_id) => {
+                lobby.room_codes.remove(&room_id);
+                lobby.room_owners.remove(&room_id);
+                Ok(Some(room_id))
             }
-            Some(Difficulty::Hard) => {}
-            None => {
-                println!("Vrajeala! nu are cum sa intre aici");
+            Err(e) => {
+                lobby.queue.insert(index, partner);
+                Err(e)
             }
         }
     }
 
-    fn danger_hex(&self, hex: (u32, u32)) -> bool {
-        let (x, y) = hex;
-        if x > 0 && y > 0 {
-            self.walls.iter().any(|&(wx, wy)| {
-                (wx == x && (wy == y + 1 || wy == y - 1))
-                    || (wy == y && (wx == x + 1 || wx == x - 1))
+    /// Everything get_update shows `viewer`: rooms, lobby chat, presence and
+    /// the queue. Private rooms are listed without their chat and moves
+    /// unless the viewer is in them.
+    fn view(&self, viewer: Option<&str>) -> Option<ServerView> {
+        let (lobby_chat, queue, announcement, shutdown_at, mut usernames) = {
+            let mut lobby = self.lobby.lock().ok()?;
+            lobby.prune_queue(self.config.queue_timeout());
+            let usernames: Vec<String> = lobby
+                .sessions
+                .iter()
+                .filter_map(|session| session.username.clone())
+                .collect();
+            (
+                lobby.lobby_chat.clone(),
+                lobby.queue.clone(),
+                lobby.announcement.clone(),
+                lobby.shutdown_at,
+                usernames,
+            )
+        };
+        let mut rooms = self.all_rooms();
+        for room in &mut rooms {
+            if room.locked && !viewer.is_some_and(|viewer| room.is_member(viewer)) {
+                room.chat.clear();
+                room.moves.clear();
+                room.starting_walls.clear();
+            }
+        }
+
+        usernames.sort();
+        usernames.dedup();
+        let presence = usernames
+            .into_iter()
+            .map(|username| Presence {
+                status: status_of(&rooms, &username),
+                username,
             })
+            .collect();
+        Some(ServerView {
+            rooms,
+            lobby_chat,
+            presence,
+            queue,
+            announcement,
+            shutdown_in: shutdown_at.map(|at| at.saturating_sub(unix_now())),
+        })
+    }
+
+    /// Refuses another room to an owner who already has as many open as the
+    /// limits allow. Rooms that are gone are forgotten first.
+    fn check_room_quota(&self, lobby: &mut Lobby, owner: &str) -> Result<(), String> {
+        let max = self.config.limits.rooms_per_user;
+        let rooms = self
+            .rooms
+            .read()
+            .map_err(|_| "rooms unavailable".to_string())?;
+        lobby
+            .room_owners
+            .retain(|room_id, _| rooms.contains_key(room_id));
+        let owned = lobby
+            .room_owners
+            .values()
+            .filter(|room_owner| *room_owner == owner)
+            .count();
+        if max > 0 && owned >= max {
+            Err(format!("you already have {} rooms open", owned))
         } else {
-            false
+            Ok(())
         }
     }
 
-    fn posib_moves(&self, mouse_x: u32, mouse_y: u32) -> Vec<(u32, u32)> {
-        let direction_par: Vec<(i32, i32)> =
-            vec![(0, 1), (0, -1), (1, -1), (1, 0), (-1, -1), (-1, 0)];
-        let direction_impar: Vec<(i32, i32)> =
-            vec![(0, 1), (0, -1), (1, 0), (1, 1), (-1, 0), (-1, 1)];
-        let direction: &Vec<(i32, i32)> = if mouse_x % 2 == 0 {
-            &direction_par
-        } else {
-            &direction_impar
+    /// Creates a multiplayer room. Rooms with a code are private: they are
+    /// listed as locked and join_room has to present the same code.
+    pub fn create_room(
+        &self,
+        owner: &str,
+        room_name: String,
+        code: Option<String>,
+    ) -> Result<u32, String> {
+        let mut lobby = self
+            .lobby
+            .lock()
+            .map_err(|_| "lobby unavailable".to_string())?;
+        self.check_room_quota(&mut lobby, owner)?;
+        let room_id = self.add_room(|room_id| {
+            let mut new_room = Room::with_board(room_id, room_name, self.config.board.clone());
+            new_room.locked = code.is_some();
+            // Private rooms are for friendly matches and do not touch ratings.
+            new_room.rated = code.is_none();
+            new_room
+        })?;
+        match code {
+            Some(code) => lobby.room_codes.insert(room_id, code),
+            None => lobby.room_codes.remove(&room_id),
         };
+        lobby.room_owners.insert(room_id, owner.to_string());
+        Ok(room_id)
+    }
+
+    pub fn create_single_room(&self, owner: &str, room_name: String) -> Result<u32, String> {
+        let mut lobby = self
+            .lobby
+            .lock()
+            .map_err(|_| "lobby unavailable".to_string())?;
+        self.check_room_quota(&mut lobby, owner)?;
+        let room_id = self.add_room(|room_id| {
+            let mut new_room = Room::with_board(room_id, room_name, self.config.board.clone());
+            new_room.room_type = RoomType::SinglePlayer;
+            new_room
+        })?;
+        lobby.room_owners.insert(room_id, owner.to_string());
+        Ok(room_id)
+    }
+
+    /// Moves the mouse or places a wall for a connection that plays `side`
+    /// in the room, if the rules allow that move now, and ends the game once
+    /// the board has a winner.
+    fn play(
+        &self,
+        client: &Client,
+        room_id: u32,
+        side: Turn,
+        (x, y): (u32, u32),
+        changes: &mut Changes,
+    ) -> Result<(), String> {
+        let owner = self.room_owner(room_id);
+        self.with_room(room_id, |room| {
+            if !plays(room, client, owner.as_deref(), &side) {
+                return Err(format!(
+                    "not the {} in room {}",
+                    role_name(Some(&side)),
+                    room_id
+                ));
+            }
+            match side {
+                Turn::MousePlayer => {
+                    room.check_mouse_move(x, y)?;
+                    room.move_mouse(x, y);
+                }
+                Turn::TrapperPlayer => {
+                    room.check_wall(x, y)?;
+                    room.place_wall(x, y);
+                }
+            }
+            changes.rooms.push(room_id);
+            if let Some(winner) = room.decided_winner() {
+                changes
+                    .records
+                    .extend(finish_game(room, Some(winner), GameEnding::Finished));
+            }
+            Ok(())
+        })
+        .unwrap_or_else(|| Err(format!("no room {}", room_id)))
+    }
 
-        let mut new_moves = Vec::new();
+    /// Stores a finished game in the match history and, for rated games with a
+    /// winner, updates both players' ratings.
+    fn archive(&self, record: MatchRecord) {
+        if let (true, Some(winner), Ok(mut accounts)) =
+            (record.rated, &record.winner, self.accounts.lock())
+        {
+            accounts.record_rated_game(
+                &record.mouse_player,
+                &record.trapper_player,
+                *winner == Turn::MousePlayer,
+            );
+        }
+        if let Ok(mut history) = self.history.lock() {
+            let match_id = history.add(record);
+            info!(match_id, "match archived");
+        }
+    }
 
-        for &(x, y) in direction {
-            let new_x = mouse_x as i32 + x;
-            let new_y = mouse_y as i32 + y;
+    /// Archives the games a command finished and writes what it changed to
+    /// the command log.
+    fn commit(&self, command: &str, mut changes: Changes) {
+        for record in changes.records.drain(..) {
+            self.archive(record);
+        }
+        if changes.is_empty() {
+            return;
+        }
+        let Ok(mut persistence) = self.persistence.lock() else {
+            return;
+        };
+        let Some(persistence) = persistence.as_mut() else {
+            return;
+        };
 
-            if new_x >= 0 && new_y >= 0 && new_x < 11 && new_y < 11 && !self.walls.contains(&(new_x as u32, new_y as u32)) {
-                    new_moves.push((new_x as u32, new_y as u32));
+        let (room_codes, room_owners, lobby_chat) = match self.lobby.lock() {
+            Ok(lobby) => (
+                changes.room_codes.then(|| lobby.room_codes.clone()),
+                changes.room_owners.then(|| lobby.room_owners.clone()),
+                changes.lobby_chat.then(|| lobby.lobby_chat.clone()),
+            ),
+            Err(_) => (None, None, None),
+        };
+        changes.rooms.sort();
+        changes.rooms.dedup();
+        let mut rooms = Vec::new();
+        let mut removed_rooms = Vec::new();
+        for room_id in changes.rooms {
+            match self.room(room_id) {
+                Some(room) => {
+                    if let Ok(room) = room.lock() {
+                        rooms.push(room.clone());
+                    }
                 }
+                None => removed_rooms.push(room_id),
+            }
         }
+        if let Err(e) = persistence.log(
+            command,
+            rooms,
+            removed_rooms,
+            room_codes,
+            room_owners,
+            lobby_chat,
+        ) {
+            error!(error = %e, "could not write the command log");
+        }
+    }
 
-        new_moves
+    /// `commit` on the blocking pool, waited for so that a command is only
+    /// answered once it is on disk.
+    async fn commit_blocking(self: &Arc<Self>, command: String, changes: Changes) {
+        if changes.is_empty() {
+            return;
+        }
+        let server = Arc::clone(self);
+        let span = Span::current();
+        let committed =
+            tokio::task::spawn_blocking(move || span.in_scope(|| server.commit(&command, changes)));
+        if committed.await.is_err() {
+            error!("could not write the command log");
+        }
     }
-}
 
-#[derive(Serialize, Deserialize)]
-struct Server {
-    rooms: Vec<Room>,
-}
+    pub fn save_snapshot(&self) {
+        let Ok(mut persistence) = self.persistence.lock() else {
+            return;
+        };
+        let Some(persistence) = persistence.as_mut() else {
+            return;
+        };
+        let (room_codes, room_owners, lobby_chat) = match self.lobby.lock() {
+            Ok(lobby) => (
+                lobby.room_codes.clone(),
+                lobby.room_owners.clone(),
+                lobby.lobby_chat.clone(),
+            ),
+            Err(_) => return,
+        };
+        let rooms = self.all_rooms();
+        if let Err(e) = persistence.snapshot(&rooms, &room_codes, &room_owners, &lobby_chat) {
+            error!(error = %e, "could not write the snapshot");
+        }
+    }
 
-impl Server {
-    pub fn new() -> Self {
-        Self { rooms: Vec::new() }
+    /// Closes the connection's session. A player whose last connection this
+    /// was keeps their seats for the reconnect grace, and gives them up if
+    /// they have not logged in again by then.
+    pub fn disconnect(self: &Arc<Self>, connection_id: u32) {
+        info!("disconnected");
+        let Some((username, departed)) = self.close_session(connection_id) else {
+            return;
+        };
+        let server = Arc::clone(self);
+        let span = Span::current();
+        let abandon = move || {
+            span.in_scope(|| {
+                let mut changes = Changes::default();
+                server.abandon(&username, departed, &mut changes);
+                server.commit("disconnect", changes);
+            })
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                let grace = self.config.reconnect_grace();
+                runtime.spawn(async move {
+                    tokio::time::sleep(grace).await;
+                    let _ = tokio::task::spawn_blocking(abandon).await;
+                });
+            }
+            Err(_) => abandon(),
+        }
+    }
+
+    /// Runs one command and returns what should be written back, if anything.
+    /// Passwords are hashed and files written on the blocking pool so neither
+    /// stalls the connections sharing a runtime thread.
+    pub async fn handle_message(self: &Arc<Self>, client: &mut Client, message: String) -> Option<String> {
+        let command = command_name(&message);
+        METRICS.command(command);
+        if message.trim() == "get_update" {
+            trace!("get_update");
+        } else {
+            info!(command = %logged_command(&message), "command received");
+        }
+
+        if let Err(reason) = self.check_limits(client, &message) {
+            return Some(over_limit(client, command, &reason));
+        }
+        if client.hello.is_none() {
+            return Some(self.hello(client, &message));
+        }
+        if !matches!(command, "login" | "register") {
+            let mut changes = Changes::default();
+            let response = self.execute(client, &message, &mut changes);
+            self.commit_blocking(logged_command(&message), changes)
+                .await;
+            return response;
+        }
+
+        let login = command == "login";
+        if login && self.logins_refused(client.peer) {
+            return Some(over_limit(
+                client,
+                command,
+                "too many failed logins, try again in a minute",
+            ));
+        }
+        let server = Arc::clone(self);
+        let hashing = self.hashing.acquire().await;
+        let result = tokio::task::spawn_blocking(move || server.authenticate(&message))
+            .await
+            .unwrap_or_else(|_| Err("login failed".to_string()));
+        drop(hashing);
+        if login && result.is_err() {
+            self.login_failed(client.peer);
+        }
+        let result = match result {
+            Ok(Some(name)) => {
+                Span::current().record("user", name.as_str());
+                info!("logged in");
+                self.login_session(client.connection_id, &name);
+                client.username = Some(name);
+                Ok("login".to_string())
+            }
+            Ok(None) => Ok("register".to_string()),
+            Err(e) => {
+                rejected(command, &e);
+                Err(e)
+            }
+        };
+        Some(reply_line(result))
     }
 
-    pub fn create_room(&mut self, room_name: String) {
-        let room_id = self.rooms.len() as u32 + 1;
-        let new_room = Room::new(room_id, room_name);
-        self.rooms.push(new_room);
+    /// The limits every command is held to, whatever it does: its size, the
+    /// connection's rates and, once logged in, the user's rate.
+    fn check_limits(&self, client: &mut Client, message: &str) -> Result<(), String> {
+        let limits = &self.config.limits;
+        if message.len() > limits.max_message_bytes {
+            return Err(format!(
+                "commands can be at most {} bytes",
+                limits.max_message_bytes
+            ));
+        }
+        if message.trim() == "get_update" {
+            return if client.updates.allow() {
+                Ok(())
+            } else {
+                Err("too many updates, slow down".to_string())
+            };
+        }
+        if !client.commands.allow() {
+            return Err("too many commands, slow down".to_string());
+        }
+        let Some(username) = &client.username else {
+            return Ok(());
+        };
+        let mut lobby = self
+            .lobby
+            .lock()
+            .map_err(|_| "lobby unavailable".to_string())?;
+        let rate = limits.user_commands_per_second;
+        let limiter = lobby
+            .user_limits
+            .entry(username.clone())
+            .or_insert_with(|| RateLimiter::new(rate, rate.saturating_mul(2)));
+        if limiter.allow() {
+            Ok(())
+        } else {
+            Err(format!("too many commands from {}, slow down", username))
+        }
     }
-    pub fn create_single_room(&mut self, room_name: String) {
-        let room_id = self.rooms.len() as u32 + 1;
-        let mut new_room = Room::new(room_id, room_name);
-        new_room.room_type = RoomType::SinglePlayer;
-        self.rooms.push(new_room);
+
+    /// Whether logins from `peer` failed too often in the last minute.
+    fn logins_refused(&self, peer: IpAddr) -> bool {
+        self.lobby.lock().is_ok_and(|mut lobby| {
+            lobby
+                .failed_logins
+                .get_mut(&peer)
+                .is_some_and(|failures| failures.exceeded())
+        })
     }
-}
 
-fn handle_client(mut stream: TcpStream, server: Arc<Mutex<Server>>) {
-    let mut buffer = [0; 1024];
-    loop {
-        match stream.read(&mut buffer) {
-            Ok(0) => break,
-            Ok(n) => {
-                let message = String::from_utf8_lossy(&buffer[..n]);
-                // println!("{}", message);
-                if message.trim() != "get_update" {
-                    println!("{}", message);
-                } 
-
-                if message.trim().contains("get_update") {
-                    let server = server.lock().unwrap();
-                    let serialized = serde_json::to_string(&*server).unwrap();
-                    if stream.write_all(serialized.as_bytes()).is_err() {
-                        break;
-                    }
-                } else if message.trim().starts_with("create_single_room") {
-                    let parts: Vec<&str> = message.split_whitespace().collect();
-                    let room_name = parts[1];
-                    if let Ok(mut server) = server.lock() {
-                        let mut x: String = room_name.to_string();
-                        x.insert(0, '!');
-
-                        server.create_single_room(x);
-                    }
-                } else if message.trim().starts_with("set_difficulty") {
-                    let parts: Vec<&str> = message.split_whitespace().collect();
-                    let difficulty = parts[1];
-                    let room_name = parts[2];
-                    if let Ok(mut server) = server.lock() {
-                        if let Some(room) = server
-                            .rooms
-                            .iter_mut()
-                            .find(|room| room.room_name == room_name)
-                        {
-                            room.game_difficulty = match difficulty {
-                                "easy" => Some(Difficulty::Easy),
-                                "medium" => Some(Difficulty::Medium),
-                                "hard" => Some(Difficulty::Hard),
-                                _ => None,
+    fn login_failed(&self, peer: IpAddr) {
+        let Ok(mut lobby) = self.lobby.lock() else {
+            return;
+        };
+        lobby
+            .failed_logins
+            .retain(|_, failures| !failures.is_clear());
+        let max = self.config.limits.failed_logins_per_minute;
+        lobby
+            .failed_logins
+            .entry(peer)
+            .or_insert_with(|| Strikes::new(max))
+            .add();
+    }
+
+    /// Every connection has to open with a hello of the same major version.
+    /// Anything else is answered with an error and the connection is closed.
+    fn hello(&self, client: &mut Client, message: &str) -> String {
+        let result = match Hello::parse(message) {
+            Some(hello) if hello.is_compatible() => {
+                debug!(
+                    version = %hello.version(),
+                    capabilities = %hello.capabilities.join(","),
+                    "hello"
+                );
+                client.hello = Some(hello);
+                Ok(Hello::new(CAPABILITIES).to_command().trim_end().to_string())
+            }
+            Some(hello) => Err(format!(
+                "incompatible protocol {}, this server speaks {}.x; please update your client",
+                hello.version(),
+                PROTOCOL_MAJOR
+            )),
+            None => Err(format!(
+                "this server speaks protocol {}.x; start with hello <version>",
+                PROTOCOL_MAJOR
+            )),
+        };
+        if let Err(e) = &result {
+            rejected("hello", e);
+        }
+        client.closing = result.is_err();
+        reply_line(result)
+    }
+
+    /// Handles register and login. Returns the username after a login.
+    fn authenticate(&self, message: &str) -> Result<Option<String>, String> {
+        let parts: Vec<&str> = message.split_whitespace().collect();
+        if message.trim().starts_with("register") {
+            match parts[..] {
+                [_, name, password] => validate_username(name)
+                    .and_then(|_| hash_password(password))
+                    .and_then(|hash| match self.accounts.lock() {
+                        Ok(mut accounts) => accounts.register(name, hash),
+                        Err(_) => Err("account store unavailable".to_string()),
+                    })
+                    .map(|_| None),
+                _ => Err("usage: register <username> <password>".to_string()),
+            }
+        } else {
+            match parts[..] {
+                [_, name, password] => {
+                    let account = self.accounts.lock().ok().and_then(|accounts| {
+                        accounts
+                            .find(name)
+                            .map(|account| (account.password_hash.clone(), account.banned))
+                    });
+                    match account {
+                        Some((hash, banned)) if verify_password(password, &hash) => {
+                            if banned {
+                                Err("this account is banned".to_string())
+                            } else {
+                                Ok(Some(name.to_string()))
                             }
                         }
+                        _ => Err("wrong username or password".to_string()),
                     }
-                } else if message.trim().starts_with("game_over") {
-                    let parts: Vec<&str> = message.split_whitespace().collect();
-                    let room_id: u32 = parts[1].parse().unwrap();
-                    let winner = parts[2];
-                    if let Ok(mut server) = server.lock() {
-                        if let Some(room) =
-                            server.rooms.iter_mut().find(|room| room.room_id == room_id)
-                        {
-                            room.game_state = GameState::GameOver;
-                            room.winner = if winner == "trapper" {
-                                Some(Turn::TrapperPlayer)
-                            } else if winner == "none" {
-                                None
-                            } else {
-                                Some(Turn::MousePlayer)
-                            };
+                }
+                _ => Err("usage: login <username> <password>".to_string()),
+            }
+        }
+    }
+
+    /// Runs a command on the state in memory. What it changed is noted in
+    /// `changes`, to be written to disk off the runtime threads.
+    fn execute(&self, client: &mut Client, message: &str, changes: &mut Changes) -> Option<String> {
+        let _room = room_span(message).entered();
+        let command = command_name(message);
+        // Commands are told apart by their whole first word.
+        let word = message.split_whitespace().next().unwrap_or("");
+        let mut response = None;
+
+        if word == "chat" {
+            let parts: Vec<&str> = message.trim().splitn(3, ' ').collect();
+            let Some(room_id) = arg::<u32>(&parts, 1) else {
+                return Some(usage(command, "chat <room> <text>"));
+            };
+            let text = parts.get(2).unwrap_or(&"");
+
+            let result = match (&client.username, check_chat(text, &mut client.chat_times)) {
+                (None, _) => Err("chat before login".to_string()),
+                (_, Err(e)) => Err(e),
+                (Some(username), Ok(text)) => self
+                    .with_room(room_id, |room| {
+                        if !room.is_member(username) {
+                            return Err(format!("{} is not in room {}", username, room_id));
                         }
+                        room.push_chat(username, &text);
+                        changes.rooms.push(room_id);
+                        Ok(())
+                    })
+                    .unwrap_or_else(|| Err(format!("no room {}", room_id))),
+            };
+            if let Err(e) = result {
+                response = refuse(client, command, &e);
+            }
+        } else if word == "lobby_chat" {
+            let text = message.trim().split_once(' ').map(|(_, text)| text).unwrap_or("");
+
+            match (&client.username, check_chat(text, &mut client.chat_times)) {
+                (None, _) => response = refuse(client, command, "chat before login"),
+                (_, Err(e)) => response = refuse(client, command, &e),
+                (Some(username), Ok(text)) => {
+                    if let Ok(mut lobby) = self.lobby.lock() {
+                        lobby.push_lobby_chat(username, &text);
+                        changes.lobby_chat = true;
                     }
-                } else if message.trim().starts_with("AI") {
-                    let parts: Vec<&str> = message.split_whitespace().collect();
-                    let room_id: u32 = parts[1].parse().unwrap();
-                    if let Ok(mut server) = server.lock() {
-                        if let Some(room) =
-                            server.rooms.iter_mut().find(|room| room.room_id == room_id)
-                        {
-                            room.ai_move();
-                        }
+                }
+            }
+        } else if word == "get_update" {
+            if let Some(view) = self.view(client.username.as_deref()) {
+                let mut serialized = serde_json::to_string(&view).unwrap();
+                serialized.push('\n');
+                response = Some(serialized);
+            }
+        } else if word == "create_single_room" {
+            // The room is named after its owner, who has one at a time, so a
+            // new one replaces the last. Older clients still send a name,
+            // which is ignored.
+            let owner = client.owner();
+            self.sweep_rooms(changes);
+            changes
+                .rooms
+                .extend(self.delete_rooms(client, |room| room.room_type == RoomType::SinglePlayer));
+            changes.room_owners = true;
+            match self.create_single_room(&owner, format!("!{}", owner)) {
+                Ok(room_id) => {
+                    info!(room_id, "single player room created");
+                    changes.rooms.push(room_id);
+                    if client.wants_replies() {
+                        response = Some(reply_line(Ok(room_id.to_string())));
                     }
-                } else if message.trim().starts_with("delete_room_by_name") {
-                    let parts: Vec<&str> = message.split_whitespace().collect();
-                    let room_name = parts[1];
-                    if let Ok(mut server) = server.lock() {
-                        server.rooms.retain(|room| room.room_name != room_name);
-                    }
-                } else if message.trim().starts_with("delete_room") {
-                    let parts: Vec<&str> = message.split_whitespace().collect();
-                    let room_id: u32 = parts[1].parse().unwrap();
-                    if let Ok(mut server) = server.lock() {
-                        server.rooms.retain(|room| room.room_id != room_id);
-                    }
-                } else if message.trim().starts_with("move_mouse") {
-                    let parts: Vec<&str> = message.split_whitespace().collect();
-                    let room_id: u32 = parts[1].parse().unwrap();
-                    let x: u32 = parts[2].parse().unwrap();
-                    let y: u32 = parts[3].parse().unwrap();
-                    if let Ok(mut server) = server.lock() {
-                        if let Some(room) =
-                            server.rooms.iter_mut().find(|room| room.room_id == room_id)
-                        {
-                            room.mouse_position = (x, y);
-                            room.turn = Turn::TrapperPlayer;
+                }
+                Err(e) => response = Some(over_limit(client, command, &e)),
+            }
+        } else if word == "set_difficulty" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let (Some(&difficulty), Some(&room_name)) = (parts.get(1), parts.get(2)) else {
+                return Some(usage(
+                    command,
+                    "set_difficulty <easy|medium|hard> <room name>",
+                ));
+            };
+            let game_difficulty = match difficulty {
+                "easy" => Difficulty::Easy,
+                "medium" => Difficulty::Medium,
+                "hard" => Difficulty::Hard,
+                _ => {
+                    return Some(usage(
+                        command,
+                        "set_difficulty <easy|medium|hard> <room name>",
+                    ))
+                }
+            };
+            // Only the owner's own rooms are looked at, so a room someone
+            // else gave the same name cannot be started.
+            let owner = client.owner();
+            let owned: Vec<u32> = match self.lobby.lock() {
+                Ok(lobby) => lobby
+                    .room_owners
+                    .iter()
+                    .filter(|(_, room_owner)| **room_owner == owner)
+                    .map(|(room_id, _)| *room_id)
+                    .collect(),
+                Err(_) => Vec::new(),
+            };
+            let result = owned
+                .into_iter()
+                .find_map(|room_id| {
+                    self.with_room(room_id, |room| {
+                        if room.room_name != room_name {
+                            return None;
                         }
-                    }
-                } else if message.trim().starts_with("place_trap") {
-                    let parts: Vec<&str> = message.split_whitespace().collect();
-                    let room_id: u32 = parts[1].parse().unwrap();
-                    let x: u32 = parts[2].parse().unwrap();
-                    let y: u32 = parts[3].parse().unwrap();
-                    if let Ok(mut server) = server.lock() {
-                        if let Some(room) =
-                            server.rooms.iter_mut().find(|room| room.room_id == room_id)
-                        {
-                            room.walls.push((x, y));
-                            room.turn = Turn::MousePlayer;
+                        if room.room_type != RoomType::SinglePlayer {
+                            return Some(Err(format!("{} is not a single player room", room_name)));
+                        }
+                        if room.game_state != GameState::Waiting {
+                            return Some(Err(format!("{} has already started", room_name)));
                         }
+                        room.game_difficulty = Some(game_difficulty.clone());
+                        room.start();
+                        info!(room_id, difficulty, "game started");
+                        changes.rooms.push(room_id);
+                        Some(Ok(()))
+                    })
+                    .flatten()
+                })
+                .unwrap_or_else(|| Err(format!("no room {} of yours", room_name)));
+            if let Err(e) = result {
+                response = refuse(client, command, &e);
+            }
+        } else if word == "game_over" {
+            // The server ends games itself once the board has a winner. A
+            // claimed winner is only taken when the board agrees, and "none"
+            // gives the game up, losing a multiplayer game to the other side.
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let winner = match parts.get(2) {
+                Some(&"mouse") => Some(Some(Turn::MousePlayer)),
+                Some(&"trapper") => Some(Some(Turn::TrapperPlayer)),
+                Some(&"none") => Some(None),
+                _ => None,
+            };
+            let (Some(room_id), Some(winner)) = (arg::<u32>(&parts, 1), winner) else {
+                return Some(usage(command, "game_over <room> <mouse|trapper|none>"));
+            };
+            let owner = self.room_owner(room_id);
+            let result = self
+                .with_room(room_id, |room| {
+                    let mouse = plays(room, client, owner.as_deref(), &Turn::MousePlayer);
+                    if !mouse && !plays(room, client, owner.as_deref(), &Turn::TrapperPlayer) {
+                        return Err(format!("not playing in room {}", room_id));
                     }
-                } else if message.trim().starts_with("create_room") {
-                    let parts: Vec<&str> = message.split_whitespace().collect();
-                    let room_name = parts[1];
-                    if !room_name.is_empty() {
-                        let mut server = server.lock().unwrap();
-                        server.create_room(room_name.to_string().clone());
-                    } 
-                } else if message.trim().starts_with("join_room") {
-                    let parts: Vec<&str> = message.split_whitespace().collect();
-                    let room_id: u32 = parts[1].parse().unwrap();
-                    let role = parts[2];
-                    let username = parts[3];
-                    if let Ok(mut server) = server.lock() {
-                        if let Some(room) =
-                            server.rooms.iter_mut().find(|room| room.room_id == room_id)
-                        {
-                            match role {
-                                "mouse" => {
-                                    room.mouse_player = Some(username.to_string());
-                                }
-                                "trapper" => {
-                                    room.trapper_player = Some(username.to_string());
-                                }
-                                _ => {
-                                    println!("ERR:role not correct");
+                    if room.game_state != GameState::InGame {
+                        return Err(format!("room {} has no game in progress", room_id));
+                    }
+                    if winner.is_some() && winner != room.decided_winner() {
+                        return Err(format!(
+                            "the board shows no {} win",
+                            role_name(winner.as_ref())
+                        ));
+                    }
+                    changes.rooms.push(room_id);
+                    if winner.is_some() || room.room_type == RoomType::SinglePlayer {
+                        changes
+                            .records
+                            .extend(finish_game(room, winner, GameEnding::Finished));
+                    } else {
+                        let other = if mouse {
+                            Turn::TrapperPlayer
+                        } else {
+                            Turn::MousePlayer
+                        };
+                        changes.records.extend(finish_game(
+                            room,
+                            Some(other),
+                            GameEnding::Forfeit(client.owner()),
+                        ));
+                    }
+                    Ok(())
+                })
+                .unwrap_or_else(|| Err(format!("no room {}", room_id)));
+            if let Err(e) = result {
+                response = refuse(client, command, &e);
+            }
+        } else if word == "ready" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let Some(room_id) = arg::<u32>(&parts, 1) else {
+                return Some(usage(command, "ready <room> [no]"));
+            };
+            let ready = parts.get(2) != Some(&"no");
+            let Some(username) = &client.username else {
+                return refuse(client, command, "not logged in");
+            };
+            let result = self
+                .with_room(room_id, |room| {
+                    if room.game_state != GameState::Waiting {
+                        return Err(format!("room {} has already started", room_id));
+                    }
+                    if room.mouse_player.as_ref() == Some(username) {
+                        room.mouse_ready = ready;
+                    } else if room.trapper_player.as_ref() == Some(username) {
+                        room.trapper_ready = ready;
+                    } else {
+                        return Err(format!("{} is not playing in room {}", username, room_id));
+                    }
+                    changes.rooms.push(room_id);
+
+                    if room.mouse_player.is_some()
+                        && room.trapper_player.is_some()
+                        && room.mouse_ready
+                        && room.trapper_ready
+                    {
+                        room.start();
+                        info!("game started");
+                    }
+                    Ok(())
+                })
+                .unwrap_or_else(|| Err(format!("no room {}", room_id)));
+            if let Err(e) = result {
+                response = refuse(client, command, &e);
+            }
+        } else if word == "join_queue" {
+            self.sweep_rooms(changes);
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let role = match parts.get(1) {
+                Some(&"mouse") => Some(QueueRole::Mouse),
+                Some(&"trapper") => Some(QueueRole::Trapper),
+                Some(&"any") => Some(QueueRole::Any),
+                _ => None,
+            };
+            let result = match (&client.username, role) {
+                (Some(username), Some(role)) => {
+                    let initial_walls = parts
+                        .get(2)
+                        .and_then(|walls| walls.parse().ok())
+                        .unwrap_or(self.config.board.initial_walls);
+                    if self.is_seated(username) {
+                        Err(format!("{} is already in a room", username))
+                    } else if initial_walls > MAX_INITIAL_WALLS {
+                        Err(format!("at most {} starting walls", MAX_INITIAL_WALLS))
+                    } else if !self.has_room_capacity() {
+                        Err("the server has no free rooms".to_string())
+                    } else {
+                        self.join_queue(username, role, BoardConfig { initial_walls })
+                            .map(|matched| {
+                                if let Some(room_id) = matched {
+                                    info!(room_id, "matched from the queue, game started");
+                                    changes.rooms.push(room_id);
+                                    changes.room_codes = true;
+                                    changes.room_owners = true;
                                 }
-                            }
-                        }
+                            })
+                    }
+                }
+                _ => Err("join_queue needs a login and a role".to_string()),
+            };
+            if let Err(e) = result {
+                response = refuse(client, command, &e);
+            }
+        } else if word == "leave_queue" {
+            if let (Some(username), Ok(mut lobby)) = (&client.username, self.lobby.lock()) {
+                lobby.leave_queue(username);
+            }
+        } else if word == "rematch" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let Some(room_id) = arg::<u32>(&parts, 1) else {
+                return Some(usage(command, "rematch <room> [swap]"));
+            };
+            let swap = parts.get(2) == Some(&"swap");
+            let Some(username) = &client.username else {
+                return refuse(client, command, "not logged in");
+            };
+            let result = self
+                .with_room(room_id, |room| {
+                    let seated = room.mouse_player.as_deref() == Some(username.as_str())
+                        || room.trapper_player.as_deref() == Some(username.as_str());
+                    if room.room_type != RoomType::MultiPlayer
+                        || room.game_state != GameState::GameOver
+                    {
+                        return Err(format!("room {} has no finished game to replay", room_id));
+                    }
+                    if !seated {
+                        return Err(format!("{} is not playing in room {}", username, room_id));
+                    }
+                    if room.mouse_player_exited || room.trapper_player_exited {
+                        return Err(format!("a player already left room {}", room_id));
+                    }
+                    if !room.rematch_votes.contains(username) {
+                        room.rematch_votes.push(username.clone());
+                    }
+                    if swap {
+                        room.rematch_swap = true;
+                    }
+                    let players = [&room.mouse_player, &room.trapper_player];
+                    if players.iter().all(|player| match player {
+                        Some(player) => room.rematch_votes.contains(player),
+                        None => false,
+                    }) {
+                        room.reset_for_rematch();
+                        info!("rematch started");
+                    }
+                    changes.rooms.push(room_id);
+                    Ok(())
+                })
+                .unwrap_or_else(|| Err(format!("no room {}", room_id)));
+            if let Err(e) = result {
+                response = refuse(client, command, &e);
+            }
+        } else if matches!(word, "AI" | "AI_Move") {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let Some(room_id) = arg::<u32>(&parts, 1) else {
+                return Some(usage(command, "AI <room>"));
+            };
+            let owner = self.room_owner(room_id);
+            let result = self
+                .with_room(room_id, |room| {
+                    if room.room_type != RoomType::SinglePlayer
+                        || !plays(room, client, owner.as_deref(), &Turn::MousePlayer)
+                    {
+                        return Err(format!("no AI of yours in room {}", room_id));
+                    }
+                    room.check_turn(&Turn::MousePlayer)?;
+                    let started = Instant::now();
+                    room.ai_move();
+                    METRICS.ai_move(started.elapsed());
+                    changes.rooms.push(room_id);
+                    if let Some(winner) = room.decided_winner() {
+                        finish_game(room, Some(winner), GameEnding::Finished);
+                    }
+                    Ok(())
+                })
+                .unwrap_or_else(|| Err(format!("no room {}", room_id)));
+            if let Err(e) = result {
+                response = refuse(client, command, &e);
+            }
+        } else if word == "delete_room_by_name" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let Some(&room_name) = parts.get(1) else {
+                return Some(usage(command, "delete_room_by_name <room name>"));
+            };
+            let removed = self.delete_rooms(client, |room| room.room_name == room_name);
+            if removed.is_empty() {
+                return refuse(
+                    client,
+                    command,
+                    &format!("no room {} you may delete", room_name),
+                );
+            }
+            changes.rooms.extend(removed);
+            changes.room_owners = true;
+            changes.room_codes = true;
+        } else if word == "delete_room" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let Some(room_id) = arg::<u32>(&parts, 1) else {
+                return Some(usage(command, "delete_room <room>"));
+            };
+            let removed = self.delete_rooms(client, |room| room.room_id == room_id);
+            if removed.is_empty() {
+                return refuse(
+                    client,
+                    command,
+                    &format!("no room {} you may delete", room_id),
+                );
+            }
+            changes.rooms.extend(removed);
+            changes.room_owners = true;
+            changes.room_codes = true;
+        } else if word == "move_mouse" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let (Some(room_id), Some(x), Some(y)) =
+                (arg(&parts, 1), arg(&parts, 2), arg(&parts, 3))
+            else {
+                return Some(usage(command, "move_mouse <room> <x> <y>"));
+            };
+            if let Err(e) = self.play(client, room_id, Turn::MousePlayer, (x, y), changes) {
+                response = refuse(client, command, &e);
+            }
+        } else if word == "place_trap" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let (Some(room_id), Some(x), Some(y)) =
+                (arg(&parts, 1), arg(&parts, 2), arg(&parts, 3))
+            else {
+                return Some(usage(command, "place_trap <room> <x> <y>"));
+            };
+            if let Err(e) = self.play(client, room_id, Turn::TrapperPlayer, (x, y), changes) {
+                response = refuse(client, command, &e);
+            }
+        } else if word == "create_room" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let Some(&room_name) = parts.get(1) else {
+                return Some(usage(command, "create_room <name> [code <code>]"));
+            };
+            let code = match (parts.get(2), parts.get(3)) {
+                (Some(&"code"), Some(code)) => Some(code.to_string()),
+                _ => None,
+            };
+            if code.as_ref().is_some_and(|code| code.len() > MAX_ROOM_CODE_LENGTH) {
+                response = refuse(
+                    client,
+                    command,
+                    &format!("room code longer than {} characters", MAX_ROOM_CODE_LENGTH),
+                );
+            } else if !room_name.is_empty() {
+                self.sweep_rooms(changes);
+                let private = code.is_some();
+                match self.create_room(&client.owner(), room_name.to_string(), code) {
+                    Ok(room_id) => {
+                        info!(room_id, private, "room created");
+                        changes.rooms.push(room_id);
+                        changes.room_codes = true;
+                        changes.room_owners = true;
                     }
-                } else if message.trim().starts_with("after_exit_room") {
-                    let parts: Vec<&str> = message.split_whitespace().collect();
-                    let room_id: u32 = parts[1].parse().unwrap();
-                    let username = parts[2];
-                    if let Ok(mut server) = server.lock() {
-                        if let Some(room) =
-                            server.rooms.iter_mut().find(|room| room.room_id == room_id)
+                    Err(e) => response = Some(over_limit(client, command, &e)),
+                }
+            }
+        } else if word == "join_room" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let (Some(room_id), Some(&role), Some(&username)) =
+                (arg::<u32>(&parts, 1), parts.get(2), parts.get(3))
+            else {
+                return Some(usage(
+                    command,
+                    "join_room <room> <mouse|trapper|spectator> <username> [code]",
+                ));
+            };
+            if client.username.as_deref() != Some(username) {
+                return refuse(
+                    client,
+                    command,
+                    &format!("join_room for {} from another session", username),
+                );
+            }
+            let code = parts.get(4).copied();
+            let code_matches = self
+                .lobby
+                .lock()
+                .is_ok_and(|lobby| lobby.code_matches(room_id, code));
+            let taken =
+                |seat: &Option<String>| seat.as_deref().is_some_and(|player| player != username);
+            let result = self
+                .with_room(room_id, |room| match role {
+                    _ if !code_matches => Err(format!("wrong code for private room {}", room_id)),
+                    "mouse" | "trapper" if room.game_state != GameState::Waiting => {
+                        Err(format!("room {} has already started", room_id))
+                    }
+                    "mouse" if room.trapper_player.as_deref() == Some(username) => {
+                        Err(format!("{} is already the trapper", username))
+                    }
+                    "trapper" if room.mouse_player.as_deref() == Some(username) => {
+                        Err(format!("{} is already the mouse", username))
+                    }
+                    "mouse" if taken(&room.mouse_player) => {
+                        Err("the mouse seat is taken".to_string())
+                    }
+                    "trapper" if taken(&room.trapper_player) => {
+                        Err("the trapper seat is taken".to_string())
+                    }
+                    "mouse" => {
+                        room.spectators.retain(|spectator| spectator != username);
+                        room.mouse_player = Some(username.to_string());
+                        room.mouse_ready = false;
+                        changes.rooms.push(room_id);
+                        Ok(())
+                    }
+                    "trapper" => {
+                        room.spectators.retain(|spectator| spectator != username);
+                        room.trapper_player = Some(username.to_string());
+                        room.trapper_ready = false;
+                        changes.rooms.push(room_id);
+                        Ok(())
+                    }
+                    "spectator" => {
+                        if room.mouse_player.as_deref() == Some(username)
+                            || room.trapper_player.as_deref() == Some(username)
                         {
+                            return Err(format!("{} already has a seat", username));
+                        }
+                        if !room.is_spectator(username) {
+                            room.spectators.push(username.to_string());
+                            changes.rooms.push(room_id);
+                        }
+                        Ok(())
+                    }
+                    _ => Err(format!("'{}' is not mouse, trapper or spectator", role)),
+                })
+                .unwrap_or_else(|| Err(format!("no room {}", room_id)));
+            if let Err(e) = result {
+                response = refuse(client, command, &e);
+            }
+        } else if word == "after_exit_room" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let Some(room_id) = arg::<u32>(&parts, 1) else {
+                return Some(usage(command, "after_exit_room <room>"));
+            };
+            let Some(username) = &client.username else {
+                return refuse(client, command, "not logged in");
+            };
+            let result = self.with_room(room_id, |room| {
+                let mouse =
+                    room.mouse_player.as_ref() == Some(username) && !room.mouse_player_exited;
+                let trapper =
+                    room.trapper_player.as_ref() == Some(username) && !room.trapper_player_exited;
+                if !mouse && !trapper {
+                    return Err(format!("{} is not playing in room {}", username, room_id));
+                }
+                if room.game_state == GameState::Waiting {
+                    return Err(format!("room {} has not started", room_id));
+                }
 
+                // Leaving a game that is still being played forfeits it.
+                let winner = if mouse {
+                    room.mouse_player_exited = true;
+                    Turn::TrapperPlayer
+                } else {
+                    room.trapper_player_exited = true;
+                    Turn::MousePlayer
+                };
+                changes.records.extend(finish_game(
+                    room,
+                    Some(winner),
+                    GameEnding::Forfeit(username.clone()),
+                ));
+                changes.rooms.push(room_id);
+                Ok(room.mouse_player_exited && room.trapper_player_exited)
+            });
+            match result {
+                Some(Ok(true)) => {
+                    self.remove_rooms(|room| room.room_id == room_id);
+                    info!("room closed, both players left");
+                }
+                Some(Ok(false)) => {}
+                Some(Err(e)) => response = refuse(client, command, &e),
+                None => response = refuse(client, command, &format!("no room {}", room_id)),
+            }
+        } else if word == "exit_room" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let (Some(room_id), Some(&username)) = (arg::<u32>(&parts, 1), parts.get(2)) else {
+                return Some(usage(command, "exit_room <room> <username>"));
+            };
+            let authenticated = client.username.as_deref() == Some(username);
 
-                            if username == "mouse" {
-                                room.mouse_player_exited = true;
-                            } else if username == "trapper" {
-                                room.trapper_player_exited = true;
-                            } 
-                            room.game_state= GameState::GameOver;
-                            if room.mouse_player_exited && room.trapper_player_exited
-                            {
-                                server.rooms.retain(|room| room.room_id != room_id);
-                            }
+            if !authenticated {
+                return refuse(
+                    client,
+                    command,
+                    &format!("exit_room for {} from another session", username),
+                );
+            }
+            let result = self
+                .with_room(room_id, |room| {
+                    if room.mouse_player.as_deref() == Some(username) {
+                        room.mouse_player = None;
+                        room.mouse_ready = false;
+                    } else if room.trapper_player.as_deref() == Some(username) {
+                        room.trapper_player = None;
+                        room.trapper_ready = false;
+                    } else if room.is_spectator(username) {
+                        room.spectators.retain(|spectator| spectator != username);
+                    } else {
+                        return Err(format!("{} is not in room {}", username, room_id));
+                    }
+                    changes.rooms.push(room_id);
+                    Ok(())
+                })
+                .unwrap_or_else(|| Err(format!("no room {}", room_id)));
+            if let Err(e) = result {
+                response = refuse(client, command, &e);
+            }
+        } else if word == "leaderboard" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let limit = parts
+                .get(2)
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(20usize)
+                .min(100);
+            let result = match (parts.get(1), self.accounts.lock()) {
+                (Some(&role), Ok(accounts)) if role == "mouse" || role == "trapper" => {
+                    let entries = accounts.leaderboard(role == "mouse", limit);
+                    Ok(serde_json::to_string(&entries).unwrap())
+                }
+                (_, Err(_)) => Err("account store unavailable".to_string()),
+                _ => Err("usage: leaderboard <mouse|trapper> [limit]".to_string()),
+            };
+            response = Some(reply_line(result));
+        } else if word == "stats" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let player = parts
+                .get(1)
+                .map(|name| name.to_string())
+                .or(client.username.clone());
+            let result = match (player, self.history.lock()) {
+                (Some(player), Ok(history)) => {
+                    Ok(serde_json::to_string(&history.stats(&player)).unwrap())
+                }
+                (None, _) => Err("usage: stats [username]".to_string()),
+                (_, Err(_)) => Err("match history unavailable".to_string()),
+            };
+            response = Some(reply_line(result));
+        } else if word == "history" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let player = parts
+                .get(1)
+                .map(|name| name.to_string())
+                .or(client.username.clone());
+            let limit = parts
+                .get(2)
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(20usize)
+                .min(100);
+            let result = match (player, self.history.lock()) {
+                (Some(player), Ok(history)) => {
+                    Ok(serde_json::to_string(&history.history(&player, limit)).unwrap())
+                }
+                (None, _) => Err("usage: history [username] [limit]".to_string()),
+                (_, Err(_)) => Err("match history unavailable".to_string()),
+            };
+            response = Some(reply_line(result));
+        } else if word == "replay" {
+            let parts: Vec<&str> = message.split_whitespace().collect();
+            let match_id = parts.get(1).and_then(|match_id| match_id.parse::<u32>().ok());
+            let result = match (match_id, self.history.lock()) {
+                (Some(match_id), Ok(history)) => match history.find(match_id) {
+                    Some(record) => Ok(serde_json::to_string(record).unwrap()),
+                    None => Err(format!("no match {}", match_id)),
+                },
+                (None, _) => Err("usage: replay <match_id>".to_string()),
+                (_, Err(_)) => Err("match history unavailable".to_string()),
+            };
+            response = Some(reply_line(result));
+        } else if word == "logout" {
+            client.username = None;
+            self.logout_session(client.connection_id);
+        } else {
+            // Unknown commands are echoed back, on a line of their own.
+            response = Some(format!("{}\n", message.trim()));
+        }
+        response
+    }
+}
 
-                        }
+/// Ends the game in a room. Returns the record to archive when this ended
+/// a multiplayer game that was being played.
+fn finish_game(room: &mut Room, winner: Option<Turn>, ending: GameEnding) -> Option<MatchRecord> {
+    if room.game_state == GameState::GameOver {
+        return None;
+    }
+    let was_playing = room.game_state == GameState::InGame;
+    info!(
+        room_id = room.room_id,
+        winner = role_name(winner.as_ref()),
+        ending = ending_name(&ending),
+        "game over"
+    );
+    if was_playing {
+        METRICS.game_finished(winner.as_ref());
+    }
+    room.game_state = GameState::GameOver;
+    room.winner = winner;
+    room.record_result();
+    if was_playing && room.room_type == RoomType::MultiPlayer {
+        MatchRecord::from_room(room, ending)
+    } else {
+        None
+    }
+}
+
+fn status_of(rooms: &[Room], username: &str) -> PresenceStatus {
+    for room in rooms {
+        if room.mouse_player.as_deref() == Some(username)
+            || room.trapper_player.as_deref() == Some(username)
+            // Single player rooms are named after their player with a leading '!'.
+            || (room.room_type == RoomType::SinglePlayer
+                && room.room_name.strip_prefix('!') == Some(username))
+        {
+            return if room.game_state == GameState::InGame {
+                PresenceStatus::InGame
+            } else {
+                PresenceStatus::InLobby
+            };
+        }
+        if room.is_spectator(username) {
+            return PresenceStatus::Spectating;
+        }
+    }
+    PresenceStatus::Idle
+}
+
+/// Whether the connection plays `side` in `room`: it holds that seat, or it
+/// owns the single player room, where it plays the trapper and moves the
+/// mouse, through the AI or, for bots, by hand.
+fn plays(room: &Room, client: &Client, owner: Option<&str>, side: &Turn) -> bool {
+    match room.room_type {
+        RoomType::SinglePlayer => owner == Some(client.owner().as_str()),
+        RoomType::MultiPlayer => {
+            let seat = match side {
+                Turn::MousePlayer => &room.mouse_player,
+                Turn::TrapperPlayer => &room.trapper_player,
+            };
+            client.username.is_some() && *seat == client.username
+        }
+    }
+}
+
+/// Whether the connection may delete `room`: it owns the room, or it played
+/// in the room and the game there is over.
+fn may_delete(room: &Room, client: &Client, owner: Option<&str>) -> bool {
+    owner == Some(client.owner().as_str())
+        || (room.game_state == GameState::GameOver
+            && (plays(room, client, owner, &Turn::MousePlayer)
+                || plays(room, client, owner, &Turn::TrapperPlayer)))
+}
+
+/// The argument at `index`, if there is one and it parses.
+fn arg<T: FromStr>(parts: &[&str], index: usize) -> Option<T> {
+    parts.get(index)?.parse().ok()
+}
+
+/// Refuses a command with missing or malformed arguments, showing how it
+/// is used.
+fn usage(command: &str, text: &str) -> String {
+    rejected(command, "malformed arguments");
+    let reason = format!("usage: {}", text);
+    if UNANSWERED.contains(&command) {
+        refusal(command, &reason)
+    } else {
+        reply_line(Err(reason))
+    }
+}
+
+fn check_chat(text: &str, chat_times: &mut Vec<Instant>) -> Result<String, String> {
+    let text: String = text.chars().filter(|c| !c.is_control()).collect();
+    let text = text.trim().to_string();
+    let now = Instant::now();
+    chat_times.retain(|sent| now.duration_since(*sent) < CHAT_WINDOW);
+
+    if text.is_empty() {
+        Err("empty chat message".to_string())
+    } else if text.chars().count() > MAX_CHAT_LENGTH {
+        Err(format!("chat message longer than {} characters", MAX_CHAT_LENGTH))
+    } else if chat_times.len() >= CHAT_BURST {
+        Err("too many chat messages".to_string())
+    } else {
+        chat_times.push(now);
+        Ok(text)
+    }
+}
+
+/// The command as it goes to the log, with passwords left out.
+fn logged_command(message: &str) -> String {
+    if message.trim().starts_with("login") || message.trim().starts_with("register") {
+        let parts: Vec<&str> = message.split_whitespace().take(2).collect();
+        format!("{} <password hidden>", parts.join(" "))
+    } else {
+        message.trim().to_string()
+    }
+}
+
+/// Logs and counts a command the server refused. Which connection and room
+/// it came from is in the enclosing spans.
+fn rejected(command: &str, reason: &str) {
+    METRICS.rejected(command);
+    warn!(command, reason, "command rejected");
+}
+
+/// Refuses a command that is not answered when it goes through. Clients
+/// that asked for replies are told why; to others it stays silent.
+fn refuse(client: &Client, command: &str, reason: &str) -> Option<String> {
+    rejected(command, reason);
+    client.wants_replies().then(|| refusal(command, reason))
+}
+
+/// Answers a command that went over a limit. A connection that keeps doing
+/// that is closed after the reply.
+fn over_limit(client: &mut Client, command: &str, reason: &str) -> String {
+    rejected(command, reason);
+    if client.strikes.add() {
+        warn!("too many limit violations, closing the connection");
+        client.closing = true;
+        limit_error(command, &format!("{}; disconnecting", reason))
+    } else {
+        limit_error(command, reason)
+    }
+}
+
+/// A span for commands addressed to one room, named by the id right after
+/// the command.
+fn room_span(message: &str) -> Span {
+    const ROOM_COMMANDS: &[&str] = &[
+        "chat",
+        "game_over",
+        "ready",
+        "rematch",
+        "AI",
+        "AI_Move",
+        "delete_room",
+        "move_mouse",
+        "place_trap",
+        "join_room",
+        "after_exit_room",
+        "exit_room",
+    ];
+    let mut parts = message.split_whitespace();
+    let command = parts.next().unwrap_or("");
+    match parts.next().and_then(|room_id| room_id.parse::<u32>().ok()) {
+        Some(room_id) if ROOM_COMMANDS.contains(&command) => info_span!("room", room_id),
+        _ => Span::none(),
+    }
+}
+
+fn role_name(role: Option<&Turn>) -> &'static str {
+    match role {
+        Some(Turn::MousePlayer) => "mouse",
+        Some(Turn::TrapperPlayer) => "trapper",
+        None => "none",
+    }
+}
+
+fn ending_name(ending: &GameEnding) -> &'static str {
+    match ending {
+        GameEnding::Finished => "finished",
+        GameEnding::Forfeit(_) => "forfeit",
+        GameEnding::Disconnect(_) => "disconnect",
+    }
+}
+
+fn reply_line(result: Result<String, String>) -> String {
+    match result {
+        Ok(message) => format!("OK:{}\n", message),
+        Err(e) => format!("ERR:{}\n", e),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Transport {
+    Tcp,
+    WebSocket,
+}
+
+impl Transport {
+    fn name(self) -> &'static str {
+        match self {
+            Transport::Tcp => "tcp",
+            Transport::WebSocket => "websocket",
+        }
+    }
+}
+
+/// Resolves once a connection has been quiet for `timeout`; never without one.
+async fn idle(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => tokio::time::sleep(timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Closes a connection's session when dropped, so that the session goes away
+/// even if handling one of its commands panics. Closing it can write the
+/// command log, so that runs on the blocking pool.
+struct SessionGuard {
+    server: Arc<Server>,
+    connection_id: u32,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let server = Arc::clone(&self.server);
+        let connection_id = self.connection_id;
+        let span = Span::current();
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(move || span.in_scope(|| server.disconnect(connection_id)));
+            }
+            Err(_) => server.disconnect(connection_id),
+        }
+    }
+}
+
+/// Splits what a TCP client sends into commands, one per line.
+struct Lines {
+    pending: Vec<u8>,
+    max_bytes: usize,
+    /// Set while the rest of a command that was too long comes in.
+    discarding: bool,
+}
+
+impl Lines {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            pending: Vec::new(),
+            max_bytes,
+            discarding: false,
+        }
+    }
+
+    /// The next whole command, without its newline. A command longer than
+    /// `max_bytes` comes out cut one byte past that, so that it is refused,
+    /// and the rest of it up to its newline is dropped.
+    fn next(&mut self) -> Option<String> {
+        loop {
+            match self.pending.iter().position(|&byte| byte == b'\n') {
+                Some(end) => {
+                    let line: Vec<u8> = self.pending.drain(..=end).collect();
+                    if std::mem::take(&mut self.discarding) {
+                        continue;
                     }
-                } else if message.trim().starts_with("exit_room") {
-                    let parts: Vec<&str> = {
-                        let this = &message;
-                        this.trim_matches(|c: char| c.is_whitespace())
-                    }.split_whitespace().collect();
-                    let room_id: u32 = parts[1].parse().unwrap();
-                    let username = parts[2];
-
-                    if let Ok(mut server) = server.lock() {
-                        if let Some(room) =
-                            server.rooms.iter_mut().find(|room| room.room_id == room_id)
-                        {
-                            if room.mouse_player == Some(username.to_string()) {
-                                room.mouse_player = None;
-                            } else if room.trapper_player == Some(username.to_string()) {
-                                room.trapper_player = None;
-                            } else {
-                                println!("ERR:Invalid username for exitting room.");
-                            }
-                        }
+                    return Some(String::from_utf8_lossy(&line[..end]).to_string());
+                }
+                None if self.pending.len() > self.max_bytes => {
+                    let line =
+                        String::from_utf8_lossy(&self.pending[..=self.max_bytes]).to_string();
+                    self.pending.clear();
+                    if std::mem::replace(&mut self.discarding, true) {
+                        return None;
                     }
-                } else if  stream.write_all(&buffer[..n]).is_err() {
-                        break;
-                    
+                    return Some(line);
                 }
+                None => return None,
             }
-            Err(_) => break,
         }
     }
+
+    /// A hello from a 1.x client, which sends commands without a newline.
+    /// It is taken as it is so that the client hears why it is refused.
+    fn old_hello(&mut self) -> Option<String> {
+        let text = String::from_utf8_lossy(&self.pending).to_string();
+        Hello::parse(&text).filter(|hello| !hello.is_compatible())?;
+        self.pending.clear();
+        Some(text)
+    }
 }
 
-fn main() -> std::io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:8080")?;
-    let server = Arc::new(Mutex::new(Server::new()));
-    listener.incoming().for_each(|stream| {
-        if let Ok(stream) = stream {
-            let server = Arc::clone(&server);
-            thread::spawn(move || {
-                handle_client(stream, server);
-            });
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    peer: IpAddr,
+    server: Arc<Server>,
+) {
+    let (connection_id, kick) = match server.open_session() {
+        Ok(session) => session,
+        Err(e) => {
+            let _ = stream.write_all(reply_line(Err(e)).as_bytes()).await;
+            return;
+        }
+    };
+    let _session = SessionGuard {
+        server: server.clone(),
+        connection_id,
+    };
+
+    let mut buffer = vec![0; 4096];
+    let mut lines = Lines::new(server.config.limits.max_message_bytes);
+    let mut client = Client::new(connection_id, peer, &server.config.limits);
+    let mut shutdown = server.shutdown.subscribe();
+    'connection: loop {
+        let n = tokio::select! {
+            read = stream.read(&mut buffer) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            },
+            _ = kick.notified() => {
+                info!("kicked by an admin");
+                let _ = stream.write_all(b"ERR:disconnected by an admin\n").await;
+                break;
+            }
+            _ = shutdown.changed() => {
+                let _ = stream.write_all(format!("{}\n", SHUTDOWN_NOTICE).as_bytes()).await;
+                let _ = stream.shutdown().await;
+                break;
+            }
+            _ = idle(server.config.idle_timeout()) => {
+                info!("closing an idle connection");
+                let _ = stream.write_all(IDLE_ERROR.as_bytes()).await;
+                break;
+            }
+        };
+        lines.pending.extend_from_slice(&buffer[..n]);
+        loop {
+            let message = match client.hello {
+                Some(_) => lines.next(),
+                None => lines.next().or_else(|| lines.old_hello()),
+            };
+            let Some(message) = message else {
+                break;
+            };
+            if let Some(response) = server.handle_message(&mut client, message).await {
+                if stream.write_all(response.as_bytes()).await.is_err() {
+                    break 'connection;
+                }
+            }
+            if client.closing {
+                let _ = stream.shutdown().await;
+                break 'connection;
+            }
+        }
+    }
+}
+
+/// Same protocol as `handle_client`, with one command per text message
+/// instead of per line, and each reply sent back as a text message.
+async fn handle_websocket<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    peer: IpAddr,
+    server: Arc<Server>,
+) {
+    // Frames well over the limit are refused by the WebSocket layer itself,
+    // which closes the connection; anything smaller gets the usual reply.
+    let max_size = Some(server.config.limits.max_message_bytes * 16);
+    let websocket_config = WebSocketConfig {
+        max_message_size: max_size,
+        max_frame_size: max_size,
+        ..Default::default()
+    };
+    let accepted = tokio_tungstenite::accept_async_with_config(stream, Some(websocket_config));
+    let mut websocket = match accepted.await {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            warn!(error = %e, "websocket handshake failed");
+            return;
+        }
+    };
+    let (connection_id, kick) = match server.open_session() {
+        Ok(session) => session,
+        Err(e) => {
+            let _ = websocket.send(Message::text(reply_line(Err(e)))).await;
+            let _ = websocket.close(None).await;
+            return;
         }
-    });
+    };
+    let _session = SessionGuard {
+        server: server.clone(),
+        connection_id,
+    };
+
+    let mut client = Client::new(connection_id, peer, &server.config.limits);
+    let mut shutdown = server.shutdown.subscribe();
+    loop {
+        let message = tokio::select! {
+            message = websocket.next() => match message {
+                Some(Ok(message)) => message,
+                _ => break,
+            },
+            _ = kick.notified() => {
+                info!("kicked by an admin");
+                let _ = websocket
+                    .send(Message::text("ERR:disconnected by an admin\n"))
+                    .await;
+                let _ = websocket.close(None).await;
+                break;
+            }
+            _ = shutdown.changed() => {
+                let _ = websocket
+                    .send(Message::text(format!("{}\n", SHUTDOWN_NOTICE)))
+                    .await;
+                let _ = websocket.close(None).await;
+                break;
+            }
+            _ = idle(server.config.idle_timeout()) => {
+                info!("closing an idle connection");
+                let _ = websocket.send(Message::text(IDLE_ERROR)).await;
+                let _ = websocket.close(None).await;
+                break;
+            }
+        };
+        let message = match message {
+            Message::Text(text) => text,
+            Message::Binary(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        if let Some(response) = server.handle_message(&mut client, message).await {
+            if websocket.send(Message::text(response)).await.is_err() {
+                break;
+            }
+        }
+        if client.closing {
+            let _ = websocket.close(None).await;
+            break;
+        }
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    peer: IpAddr,
+    server: Arc<Server>,
+    transport: Transport,
+) {
+    match transport {
+        Transport::Tcp => handle_client(stream, peer, server).await,
+        Transport::WebSocket => handle_websocket(stream, peer, server).await,
+    }
+}
+
+/// Accepts connections on `listener`, each on its own task. With TLS the
+/// handshake runs on that task too, so a slow client cannot hold up others.
+async fn serve(
+    listener: TcpListener,
+    server: Arc<Server>,
+    tls: Option<TlsAcceptor>,
+    transport: Transport,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!(error = %e, "accept failed");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let server = Arc::clone(&server);
+        let tls = tls.clone();
+        // Everything logged for this connection carries these fields; the
+        // id and the user are filled in once they are known.
+        let span = info_span!(
+            "connection",
+            transport = transport.name(),
+            %peer,
+            connection_id = tracing::field::Empty,
+            user = tracing::field::Empty,
+        );
+        tokio::spawn(
+            async move {
+                match tls {
+                    Some(tls) => match tls.accept(stream).await {
+                        Ok(stream) => handle_connection(stream, peer.ip(), server, transport).await,
+                        Err(e) => warn!(error = %e, "TLS handshake failed"),
+                    },
+                    None => handle_connection(stream, peer.ip(), server, transport).await,
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let config = match config::parse_options() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    logging::init(config.log_level, config.log_format);
+    let tls = match &config.tls {
+        Some(tls_config) => match tls::acceptor(tls_config) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        },
+        None => None,
+    };
+    let listener = TcpListener::bind(&config.bind).await?;
+    info!(address = %config.bind, tls = tls.is_some(), "listening");
+    let websocket_listener = match &config.websocket_bind {
+        Some(address) => {
+            let listener = TcpListener::bind(address).await?;
+            info!(%address, tls = tls.is_some(), "listening for WebSocket clients");
+            Some(listener)
+        }
+        None => None,
+    };
+    std::fs::create_dir_all(&config.persistence.data_dir)?;
+    let accounts = AccountStore::load(config.data_path("accounts.json"))?;
+    let history = MatchHistory::load(config.data_path("matches.jsonl"))?;
+
+    let mut server = Server::new(config.clone(), accounts, history);
+    if config.persistence.enabled {
+        let (persistence, snapshot) = Persistence::open(
+            config.data_path("snapshot.json"),
+            config.data_path("commands.wal"),
+        )?;
+        server.restore(persistence, snapshot);
+    }
+    let server = Arc::new(server);
+
+    {
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(server.config.snapshot_interval());
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let server = Arc::clone(&server);
+                let _ = tokio::task::spawn_blocking(move || {
+                    let mut changes = Changes::default();
+                    server.sweep_rooms(&mut changes);
+                    server.commit("sweep", changes);
+                    server.save_snapshot();
+                })
+                .await;
+            }
+        });
+    }
+
+    if let Some(address) = &config.metrics_bind {
+        let listener = TcpListener::bind(address).await?;
+        info!(%address, "metrics listening");
+        tokio::spawn(metrics::serve(listener, Arc::clone(&server)));
+    }
+    if let Some(admin) = &config.admin {
+        let listener = TcpListener::bind(&admin.bind).await?;
+        info!(address = %admin.bind, "admin console listening");
+        let token = admin.token.clone();
+        tokio::spawn(admin::serve(listener, Arc::clone(&server), token));
+    }
+    let mut listeners = vec![tokio::spawn(serve(
+        listener,
+        Arc::clone(&server),
+        tls.clone(),
+        Transport::Tcp,
+    ))];
+    if let Some(websocket_listener) = websocket_listener {
+        listeners.push(tokio::spawn(serve(
+            websocket_listener,
+            Arc::clone(&server),
+            tls,
+            Transport::WebSocket,
+        )));
+    }
+
+    shutdown::signal().await;
+    for listener in &listeners {
+        listener.abort();
+    }
+    server.shut_down().await;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Changes, Client, Server};
+    use crate::accounts::AccountStore;
+    use crate::config::Config;
+    use crate::game::{GameState, Room, Turn};
+    use crate::history::MatchHistory;
+    use crate::protocol::{Hello, LIMIT_ERROR};
+    use std::net::IpAddr;
+
+    /// A server keeping its accounts and match history in a fresh
+    /// directory of its own.
+    fn server(name: &str, config: Config) -> Server {
+        let dir = std::env::temp_dir().join(format!(
+            "trapthemouse-server-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("create data directory");
+        let accounts = AccountStore::load(dir.join("accounts.json")).expect("load accounts");
+        let history = MatchHistory::load(dir.join("matches.jsonl")).expect("load history");
+        Server::new(config, accounts, history)
+    }
+
+    /// A connection that asked for replies, logged in as `username` if given.
+    fn connect(server: &Server, username: Option<&str>) -> Client {
+        let (connection_id, _) = server.open_session().expect("open session");
+        let mut client = Client::new(
+            connection_id,
+            IpAddr::from([127, 0, 0, 1]),
+            &server.config.limits,
+        );
+        client.hello = Hello::parse("hello 2.1 replies");
+        if let Some(username) = username {
+            client.username = Some(username.to_string());
+            server.login_session(connection_id, username);
+        }
+        client
+    }
+
+    /// Runs one command the way a connection does, archiving what it
+    /// finished. Returns the reply, if any.
+    fn run(server: &Server, client: &mut Client, message: &str) -> Option<String> {
+        let mut changes = Changes::default();
+        let reply = server.execute(client, message, &mut changes);
+        server.commit(message, changes);
+        reply
+    }
+
+    fn room(server: &Server, room_id: u32) -> Option<Room> {
+        server.with_room(room_id, |room| room.clone())
+    }
+
+    fn newest_room(server: &Server) -> u32 {
+        server
+            .all_rooms()
+            .last()
+            .map(|room| room.room_id)
+            .expect("a room")
+    }
+
+    /// Seats alice as the mouse and bob as the trapper in a new room, both
+    /// ready, so the game has started.
+    fn start_game(server: &Server, alice: &mut Client, bob: &mut Client) -> u32 {
+        assert_eq!(run(server, alice, "create_room duel"), None);
+        let room_id = newest_room(server);
+        let join_mouse = format!("join_room {} mouse alice", room_id);
+        let join_trapper = format!("join_room {} trapper bob", room_id);
+        let ready = format!("ready {}", room_id);
+        assert_eq!(run(server, alice, &join_mouse), None);
+        assert_eq!(run(server, bob, &join_trapper), None);
+        assert_eq!(run(server, alice, &ready), None);
+        assert_eq!(run(server, bob, &ready), None);
+        room_id
+    }
+
+    fn refused(reply: Option<String>, command: &str) -> bool {
+        reply.is_some_and(|reply| reply.starts_with(&format!("ERR:{} ", command)))
+    }
+
+    #[test]
+    fn malformed_commands_are_answered_with_their_usage() {
+        let server = server("usage", Config::default());
+        let mut alice = connect(&server, Some("alice"));
+        for message in [
+            "chat",
+            "ready",
+            "ready one",
+            "rematch",
+            "AI x",
+            "game_over 1 maybe",
+            "set_difficulty",
+            "set_difficulty impossible !alice",
+            "delete_room",
+            "delete_room first",
+            "delete_room_by_name",
+            "move_mouse 1 2",
+            "place_trap 1 x 3",
+            "place_trap -1 2 3",
+            "create_room",
+            "join_room 1 mouse",
+            "after_exit_room",
+            "exit_room 1",
+        ] {
+            let reply = run(&server, &mut alice, message).unwrap_or_default();
+            assert!(
+                reply.starts_with("ERR:") && reply.contains("usage: "),
+                "{} got {:?}",
+                message,
+                reply
+            );
+        }
+    }
+
+    #[test]
+    fn a_game_starts_once_both_players_are_ready() {
+        let server = server("ready", Config::default());
+        let mut alice = connect(&server, Some("alice"));
+        let mut bob = connect(&server, Some("bob"));
+        let mut carol = connect(&server, Some("carol"));
+        run(&server, &mut alice, "create_room duel");
+        let room_id = newest_room(&server);
+        run(
+            &server,
+            &mut alice,
+            &format!("join_room {} mouse alice", room_id),
+        );
+        run(
+            &server,
+            &mut bob,
+            &format!("join_room {} trapper bob", room_id),
+        );
+
+        assert_eq!(
+            run(&server, &mut alice, &format!("ready {}", room_id)),
+            None
+        );
+        assert!(refused(
+            run(&server, &mut carol, &format!("ready {}", room_id)),
+            "ready"
+        ));
+        assert!(room(&server, room_id).unwrap().game_state == GameState::Waiting);
+
+        assert_eq!(run(&server, &mut bob, &format!("ready {}", room_id)), None);
+        assert!(room(&server, room_id).unwrap().game_state == GameState::InGame);
+        assert!(refused(
+            run(
+                &server,
+                &mut bob,
+                &format!("join_room {} mouse bob", room_id)
+            ),
+            "join_room"
+        ));
+    }
+
+    #[test]
+    fn only_the_side_to_play_moves() {
+        let server = server("moves", Config::default());
+        let mut alice = connect(&server, Some("alice"));
+        let mut bob = connect(&server, Some("bob"));
+        let room_id = start_game(&server, &mut alice, &mut bob);
+        let mut carol = connect(&server, Some("carol"));
+        let wall = room(&server, room_id)
+            .unwrap()
+            .posib_moves(5, 5)
+            .into_iter()
+            .next()
+            .expect("a free hex next to the mouse");
+
+        let place = format!("place_trap {} {} {}", room_id, wall.0, wall.1);
+        assert!(refused(run(&server, &mut alice, &place), "place_trap"));
+        assert!(refused(run(&server, &mut carol, &place), "place_trap"));
+        assert!(refused(
+            run(&server, &mut alice, &format!("move_mouse {} 5 6", room_id)),
+            "move_mouse"
+        ));
+        assert_eq!(run(&server, &mut bob, &place), None);
+        assert!(refused(run(&server, &mut bob, &place), "place_trap"));
+
+        let after = room(&server, room_id).unwrap();
+        assert!(after.walls.contains(&wall));
+        assert!(after.turn == Turn::MousePlayer);
+    }
+
+    #[test]
+    fn private_rooms_are_joined_with_their_code() {
+        let server = server("private", Config::default());
+        let mut alice = connect(&server, Some("alice"));
+        let mut bob = connect(&server, Some("bob"));
+        run(&server, &mut alice, "create_room hideout code s3cret");
+        let room_id = newest_room(&server);
+        assert!(room(&server, room_id).unwrap().locked);
+
+        for message in [
+            format!("join_room {} mouse bob", room_id),
+            format!("join_room {} spectator bob wrong", room_id),
+        ] {
+            assert!(
+                refused(run(&server, &mut bob, &message), "join_room"),
+                "{}",
+                message
+            );
+        }
+        assert_eq!(
+            run(
+                &server,
+                &mut bob,
+                &format!("join_room {} mouse bob s3cret", room_id)
+            ),
+            None
+        );
+        assert_eq!(
+            room(&server, room_id).unwrap().mouse_player.as_deref(),
+            Some("bob")
+        );
+        assert!(!room(&server, room_id).unwrap().rated);
+    }
+
+    #[test]
+    fn owners_get_as_many_rooms_as_the_quota_allows() {
+        let mut config = Config::default();
+        config.limits.rooms_per_user = 2;
+        let server = server("quota", config);
+        let mut alice = connect(&server, Some("alice"));
+        let mut bob = connect(&server, Some("bob"));
+
+        assert_eq!(run(&server, &mut alice, "create_room one"), None);
+        assert_eq!(run(&server, &mut alice, "create_room two"), None);
+        let reply = run(&server, &mut alice, "create_room three").unwrap_or_default();
+        assert!(reply.starts_with(LIMIT_ERROR), "{}", reply);
+        assert_eq!(run(&server, &mut bob, "create_room three"), None);
+
+        assert_eq!(run(&server, &mut alice, "delete_room_by_name one"), None);
+        assert_eq!(run(&server, &mut alice, "create_room three"), None);
+    }
+
+    #[test]
+    fn rooms_nobody_comes_back_to_are_swept() {
+        let mut config = Config::default();
+        config.timers.reconnect_grace_secs = 0;
+        let server = server("sweep", config);
+        let mut guest = connect(&server, None);
+        run(&server, &mut guest, "create_room abandoned");
+        server.close_session(guest.connection_id);
+
+        let mut alice = connect(&server, Some("alice"));
+        run(&server, &mut alice, "create_room fresh");
+        let names: Vec<String> = server
+            .all_rooms()
+            .into_iter()
+            .map(|room| room.room_name)
+            .collect();
+        assert_eq!(names, vec!["fresh".to_string()]);
+    }
+
+    #[test]
+    fn only_the_owner_deletes_a_room_before_its_game_is_over() {
+        let server = server("delete", Config::default());
+        let mut alice = connect(&server, Some("alice"));
+        let mut bob = connect(&server, Some("bob"));
+        let room_id = start_game(&server, &mut alice, &mut bob);
+
+        let delete = format!("delete_room {}", room_id);
+        assert!(refused(run(&server, &mut bob, &delete), "delete_room"));
+        assert!(room(&server, room_id).is_some());
+
+        run(&server, &mut alice, &format!("game_over {} none", room_id));
+        assert_eq!(run(&server, &mut bob, &delete), None);
+        assert!(room(&server, room_id).is_none());
+    }
+
+    #[test]
+    fn set_difficulty_starts_only_your_own_single_player_room() {
+        let server = server("single", Config::default());
+        let mut alice = connect(&server, Some("alice"));
+        let mut bob = connect(&server, Some("bob"));
+        let reply = run(&server, &mut alice, "create_single_room ").unwrap_or_default();
+        let room_id: u32 = reply
+            .trim()
+            .strip_prefix("OK:")
+            .and_then(|room_id| room_id.parse().ok())
+            .expect("the new room's id");
+        assert_eq!(room(&server, room_id).unwrap().room_name, "!alice");
+
+        assert!(refused(
+            run(&server, &mut bob, "set_difficulty easy !alice"),
+            "set_difficulty"
+        ));
+        assert!(room(&server, room_id).unwrap().game_state == GameState::Waiting);
+        assert_eq!(run(&server, &mut alice, "set_difficulty hard !alice"), None);
+        assert!(room(&server, room_id).unwrap().game_state == GameState::InGame);
+        assert!(refused(
+            run(&server, &mut alice, "set_difficulty easy !alice"),
+            "set_difficulty"
+        ));
+    }
+
+    #[test]
+    fn a_rematch_with_swap_trades_sides() {
+        let server = server("rematch", Config::default());
+        let mut alice = connect(&server, Some("alice"));
+        let mut bob = connect(&server, Some("bob"));
+        let mut carol = connect(&server, Some("carol"));
+        let room_id = start_game(&server, &mut alice, &mut bob);
+        run(&server, &mut alice, &format!("game_over {} none", room_id));
+        assert!(room(&server, room_id).unwrap().winner == Some(Turn::TrapperPlayer));
+
+        assert!(refused(
+            run(&server, &mut carol, &format!("rematch {}", room_id)),
+            "rematch"
+        ));
+        assert_eq!(
+            run(&server, &mut alice, &format!("rematch {} swap", room_id)),
+            None
+        );
+        assert!(room(&server, room_id).unwrap().game_state == GameState::GameOver);
+        assert_eq!(
+            run(&server, &mut bob, &format!("rematch {}", room_id)),
+            None
+        );
+
+        let rematch = room(&server, room_id).unwrap();
+        assert!(rematch.game_state == GameState::InGame);
+        assert_eq!(rematch.mouse_player.as_deref(), Some("bob"));
+        assert_eq!(rematch.trapper_player.as_deref(), Some("alice"));
+        assert_eq!(rematch.series_score, vec![("bob".to_string(), 1)]);
+    }
+
+    #[test]
+    fn a_rated_game_moves_both_ratings() {
+        let server = server("ratings", Config::default());
+        for username in ["alice", "bob"] {
+            server
+                .accounts
+                .lock()
+                .unwrap()
+                .register(username, "unused hash".to_string())
+                .expect("register");
+        }
+        let mut alice = connect(&server, Some("alice"));
+        let mut bob = connect(&server, Some("bob"));
+        assert_eq!(run(&server, &mut alice, "join_queue mouse"), None);
+        assert_eq!(run(&server, &mut bob, "join_queue trapper"), None);
+        let room_id = newest_room(&server);
+        assert!(room(&server, room_id).unwrap().rated);
+
+        run(&server, &mut alice, &format!("game_over {} none", room_id));
+        let accounts = server.accounts.lock().unwrap();
+        let mouse = &accounts.find("alice").unwrap().mouse_rating;
+        let trapper = &accounts.find("bob").unwrap().trapper_rating;
+        assert_eq!((mouse.games, trapper.games), (1, 1));
+        assert!(mouse.rating < 1500.0 && trapper.rating > 1500.0);
+        assert!((1500.0 - mouse.rating - (trapper.rating - 1500.0)).abs() < 1e-9);
+    }
+}
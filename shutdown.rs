@@ -0,0 +1,79 @@
+//! Graceful shutdown. On SIGINT or SIGTERM the listeners stop, clients see a
+//! countdown in get_update, then every connection is told and closed and a
+//! last snapshot is written.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tracing::{error, info, warn};
+
+use crate::game::unix_now;
+use crate::Server;
+
+/// How long closed connections get to finish before the snapshot is taken
+/// anyway.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves on SIGINT or, on Unix, SIGTERM.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => error!(error = %e, "cannot listen for SIGTERM"),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!(error = %e, "cannot listen for SIGINT");
+        std::future::pending::<()>().await;
+    }
+}
+
+impl Server {
+    /// Counts down, closes every connection and writes a last snapshot.
+    /// Games in progress are kept in it rather than lost by disconnect.
+    pub async fn shut_down(self: &Arc<Self>) {
+        let grace = self.config.timers.shutdown_grace_secs;
+        info!(grace_secs = grace, "shutting down");
+        if let Ok(mut lobby) = self.lobby.lock() {
+            lobby.shutdown_at = Some(unix_now() + grace);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(grace)) => {}
+            _ = tokio::signal::ctrl_c() => info!("interrupted again, closing now"),
+        }
+
+        self.shutdown.send_replace(true);
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        while self
+            .lobby
+            .lock()
+            .is_ok_and(|lobby| !lobby.sessions.is_empty())
+        {
+            if Instant::now() >= deadline {
+                warn!("some connections did not close in time");
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        if self.config.persistence.enabled {
+            let server = Arc::clone(self);
+            if tokio::task::spawn_blocking(move || server.save_snapshot())
+                .await
+                .is_err()
+            {
+                error!("could not write the last snapshot");
+            }
+        }
+        info!("shut down");
+    }
+}
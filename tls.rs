@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path)
+        .map_err(|e| format!("could not read certificate {}: {}", path.display(), e))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid certificate {}: {}", path.display(), e))?;
+    if certificates.is_empty() {
+        return Err(format!("no certificate in {}", path.display()));
+    }
+    Ok(certificates)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path)
+        .map_err(|e| format!("could not read private key {}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("invalid private key {}: {}", path.display(), e))?
+        .ok_or_else(|| format!("no private key in {}", path.display()))
+}
+
+/// Builds the acceptor that wraps every incoming connection, on both the
+/// TCP and the WebSocket listener, in TLS.
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor, String> {
+    let certificates = load_certificates(&config.cert_path)?;
+    let key = load_private_key(&config.key_path)?;
+    let server_config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|e| e.to_string())?
+    .with_no_client_auth()
+    .with_single_cert(certificates, key)
+    .map_err(|e| format!("certificate and key do not match: {}", e))?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::acceptor;
+    use crate::config::TlsConfig;
+    use crate::tls_client;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpStream;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::runtime::Runtime;
+
+    /// Writes a fresh self-signed certificate for localhost and its key to
+    /// PEM files. The certificate doubles as the CA a client trusts.
+    fn self_signed(name: &str) -> TlsConfig {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .expect("generate certificate");
+        let dir =
+            std::env::temp_dir().join(format!("trapthemouse-tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).expect("create certificate directory");
+        let config = TlsConfig {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+        };
+        std::fs::write(&config.cert_path, certified.cert.pem()).expect("write certificate");
+        std::fs::write(&config.key_path, certified.key_pair.serialize_pem()).expect("write key");
+        config
+    }
+
+    /// Accepts one TLS connection and greets it with a line. Returns the
+    /// address to connect to.
+    fn serve_once(runtime: &Runtime, config: &TlsConfig) -> String {
+        let acceptor = acceptor(config).expect("build acceptor");
+        let listener = runtime
+            .block_on(TcpListener::bind("127.0.0.1:0"))
+            .expect("bind listener");
+        let port = listener.local_addr().expect("listener address").port();
+        runtime.spawn(async move {
+            let (stream, _) = listener.accept().await.expect("accept");
+            if let Ok(mut stream) = acceptor.accept(stream).await {
+                let _ = stream.write_all(b"OK:hello\n").await;
+                let _ = stream.shutdown().await;
+            }
+        });
+        format!("localhost:{}", port)
+    }
+
+    fn ca_path(config: &TlsConfig) -> String {
+        config.cert_path.display().to_string()
+    }
+
+    #[test]
+    fn client_trusting_the_certificate_connects() {
+        let runtime = Runtime::new().expect("runtime");
+        let server = self_signed("trusted");
+        let address = serve_once(&runtime, &server);
+
+        let stream = TcpStream::connect(&address).expect("connect");
+        let stream = tls_client::connect(stream, &address, &ca_path(&server))
+            .expect("handshake with a trusted certificate");
+        let mut line = String::new();
+        BufReader::new(stream)
+            .read_line(&mut line)
+            .expect("read greeting");
+        assert_eq!(line, "OK:hello\n");
+    }
+
+    #[test]
+    fn client_trusting_another_ca_is_refused() {
+        let runtime = Runtime::new().expect("runtime");
+        let server = self_signed("server");
+        let other = self_signed("other");
+        let address = serve_once(&runtime, &server);
+
+        let stream = TcpStream::connect(&address).expect("connect");
+        match tls_client::connect(stream, &address, &ca_path(&other)) {
+            Ok(_) => panic!("handshake succeeded with an untrusted certificate"),
+            Err(error) => assert!(error.contains("TLS handshake"), "{}", error),
+        }
+    }
+}
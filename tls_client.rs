@@ -0,0 +1,58 @@
+//! The client side of TLS, shared by the native window client and the
+//! command-line client. The server's tests connect through it as well.
+
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::sync::Arc;
+
+/// Wraps `stream`, already connected to `address` (host:port), in TLS and
+/// finishes the handshake, so a bad certificate is reported as a failed
+/// connection rather than on the first command.
+pub fn connect(
+    mut stream: TcpStream,
+    address: &str,
+    trusted_ca: &str,
+) -> Result<rustls::StreamOwned<rustls::ClientConnection, TcpStream>, String> {
+    let host = address
+        .rsplit_once(':')
+        .map_or(address, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let server_name = rustls::pki_types::ServerName::try_from(host.to_string())
+        .map_err(|e| format!("Invalid server name {}: {}", host, e))?;
+    let mut connection = rustls::ClientConnection::new(client_config(trusted_ca)?, server_name)
+        .map_err(|e| e.to_string())?;
+    while connection.is_handshaking() {
+        connection
+            .complete_io(&mut stream)
+            .map_err(|e| format!("TLS handshake with {} failed: {}", address, e))?;
+    }
+    Ok(rustls::StreamOwned::new(connection, stream))
+}
+
+/// Trusts the certificates in the PEM file `trusted_ca`, or the usual web
+/// roots when it is empty.
+pub fn client_config(trusted_ca: &str) -> Result<Arc<rustls::ClientConfig>, String> {
+    let mut roots = rustls::RootCertStore::empty();
+    if trusted_ca.trim().is_empty() {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    } else {
+        let path = trusted_ca.trim();
+        let file =
+            std::fs::File::open(path).map_err(|e| format!("Could not read CA {}: {}", path, e))?;
+        for certificate in rustls_pemfile::certs(&mut BufReader::new(file)) {
+            let certificate = certificate.map_err(|e| format!("Invalid CA {}: {}", path, e))?;
+            roots
+                .add(certificate)
+                .map_err(|e| format!("Invalid CA {}: {}", path, e))?;
+        }
+    }
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|e| e.to_string())?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    Ok(Arc::new(config))
+}
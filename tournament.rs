@@ -0,0 +1,547 @@
+#[allow(dead_code)]
+mod game;
+#[allow(dead_code)]
+mod protocol;
+
+use game::{Difficulty, GameState, Room, RoomType, Turn};
+use protocol::{parse_limit_error, parse_refusal, Hello};
+use rand::prelude::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+#[derive(Clone, PartialEq)]
+enum MouseEngine {
+    Easy,
+    Medium,
+    Runner,
+}
+
+#[derive(Clone, PartialEq)]
+enum TrapperEngine {
+    Random,
+    Blocker,
+}
+
+enum Transport {
+    Local,
+    Tcp(String),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Outcome {
+    MouseWon,
+    TrapperWon,
+    Unfinished,
+}
+
+#[derive(Deserialize)]
+struct ServerSnapshot {
+    rooms: Vec<Room>,
+}
+
+#[derive(Serialize)]
+struct PairingSummary {
+    mouse: String,
+    trapper: String,
+    games: u32,
+    mouse_wins: u32,
+    trapper_wins: u32,
+    unfinished: u32,
+    mouse_win_rate: f64,
+    mouse_win_rate_ci_low: f64,
+    mouse_win_rate_ci_high: f64,
+    trapper_win_rate: f64,
+    trapper_win_rate_ci_low: f64,
+    trapper_win_rate_ci_high: f64,
+    avg_game_length: f64,
+}
+
+struct Options {
+    games: u32,
+    mice: Vec<MouseEngine>,
+    trappers: Vec<TrapperEngine>,
+    transport: Transport,
+    format: String,
+    max_turns: u32,
+}
+
+impl MouseEngine {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "easy" => Ok(MouseEngine::Easy),
+            "medium" => Ok(MouseEngine::Medium),
+            "hard" => Err("the hard AI does not move yet".to_string()),
+            "runner" => Ok(MouseEngine::Runner),
+            _ => Err(format!("unknown mouse engine '{}'", name)),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            MouseEngine::Easy => "easy",
+            MouseEngine::Medium => "medium",
+            MouseEngine::Runner => "runner",
+        }
+    }
+
+    fn difficulty(&self) -> Option<Difficulty> {
+        match self {
+            MouseEngine::Easy => Some(Difficulty::Easy),
+            MouseEngine::Medium => Some(Difficulty::Medium),
+            MouseEngine::Runner => None,
+        }
+    }
+
+    /// Where the mouse wants to go next, for the engines that are not `ai_move`.
+    fn pick(&self, room: &Room) -> Option<(u32, u32)> {
+        let (mouse_x, mouse_y) = room.mouse_position;
+        match escape_path(room) {
+            Some(path) if path.len() > 1 => Some(path[1]),
+            _ => room
+                .posib_moves(mouse_x, mouse_y)
+                .choose(&mut rand::thread_rng())
+                .cloned(),
+        }
+    }
+}
+
+impl TrapperEngine {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "random" => Ok(TrapperEngine::Random),
+            "blocker" => Ok(TrapperEngine::Blocker),
+            _ => Err(format!("unknown trapper engine '{}'", name)),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            TrapperEngine::Random => "random",
+            TrapperEngine::Blocker => "blocker",
+        }
+    }
+
+    fn pick(&self, room: &Room) -> Option<(u32, u32)> {
+        let (mouse_x, mouse_y) = room.mouse_position;
+        match self {
+            TrapperEngine::Random => free_hexes(room).choose(&mut rand::thread_rng()).cloned(),
+            TrapperEngine::Blocker => match escape_path(room) {
+                Some(path) if path.len() > 1 => Some(path[1]),
+                _ => room
+                    .posib_moves(mouse_x, mouse_y)
+                    .choose(&mut rand::thread_rng())
+                    .cloned(),
+            },
+        }
+    }
+}
+
+fn free_hexes(room: &Room) -> Vec<(u32, u32)> {
+    let mut hexes = Vec::new();
+    for x in 0..11 {
+        for y in 0..11 {
+            if (x, y) != room.mouse_position && !room.walls.contains(&(x, y)) {
+                hexes.push((x, y));
+            }
+        }
+    }
+    hexes
+}
+
+/// Shortest path from the mouse to the edge of the board, mouse included.
+fn escape_path(room: &Room) -> Option<Vec<(u32, u32)>> {
+    let start = room.mouse_position;
+    let mut came_from: HashMap<(u32, u32), (u32, u32)> = HashMap::new();
+    let mut queue = VecDeque::from([start]);
+
+    while let Some(hex) = queue.pop_front() {
+        let (x, y) = hex;
+        if x == 0 || x == 10 || y == 0 || y == 10 {
+            let mut path = vec![hex];
+            let mut current = hex;
+            while let Some(&previous) = came_from.get(&current) {
+                path.push(previous);
+                current = previous;
+            }
+            path.reverse();
+            return Some(path);
+        }
+        for next in room.posib_moves(x, y) {
+            if next != start && !came_from.contains_key(&next) {
+                came_from.insert(next, hex);
+                queue.push_back(next);
+            }
+        }
+    }
+    None
+}
+
+fn play_local(mouse: &MouseEngine, trapper: &TrapperEngine, max_turns: u32) -> (Outcome, u32) {
+    let mut room = Room::new(0, "tournament".to_string());
+    room.room_type = RoomType::SinglePlayer;
+    room.game_difficulty = mouse.difficulty();
+    room.game_state = GameState::InGame;
+
+    for turn in 1..=max_turns {
+        match trapper.pick(&room) {
+            Some((x, y)) => room.place_wall(x, y),
+            None => return (Outcome::MouseWon, turn),
+        }
+        if room.mouse_trapped() {
+            return (Outcome::TrapperWon, turn);
+        }
+
+        match mouse {
+            MouseEngine::Runner => {
+                if let Some((x, y)) = mouse.pick(&room) {
+                    room.move_mouse(x, y);
+                }
+            }
+            _ => room.ai_move(),
+        }
+        if room.winner == Some(Turn::TrapperPlayer) || room.turn == Turn::MousePlayer {
+            return (Outcome::TrapperWon, turn);
+        }
+        if room.mouse_escaped() {
+            return (Outcome::MouseWon, turn);
+        }
+    }
+    (Outcome::Unfinished, max_turns)
+}
+
+/// Sends one command, on a line of its own.
+fn send(stream: &mut TcpStream, command: &str) -> std::io::Result<()> {
+    stream.write_all(format!("{}\n", command).as_bytes())
+}
+
+/// Opens the connection with a hello, as every client has to.
+fn hello(stream: &mut TcpStream) -> std::io::Result<()> {
+    send(stream, &Hello::new(&["replies"]).to_command())?;
+    let reply = read_reply(stream)?;
+    match Hello::parse(&reply) {
+        Some(server) if server.is_compatible() => Ok(()),
+        _ => Err(std::io::Error::other(reply)),
+    }
+}
+
+/// Reads the answer to a command that is answered with one line.
+fn read_reply(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut data = Vec::new();
+    let mut buffer = [0; 1024];
+    while !data.ends_with(b"\n") {
+        let n = stream.read(&mut buffer)?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        data.extend_from_slice(&buffer[..n]);
+    }
+    let line = String::from_utf8_lossy(&data).trim().to_string();
+    match line.strip_prefix("OK:") {
+        Some(reply) => Ok(reply.to_string()),
+        None => Err(std::io::Error::other(line)),
+    }
+}
+
+fn fetch_room(stream: &mut TcpStream, room_id: u32) -> std::io::Result<Option<Room>> {
+    send(stream, "get_update")?;
+    let mut data = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        let n = stream.read(&mut buffer)?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        data.extend_from_slice(&buffer[..n]);
+        while let Some(end) = data.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = data.drain(..=end).collect();
+            if let Ok(snapshot) = serde_json::from_slice::<ServerSnapshot>(&line) {
+                return Ok(snapshot
+                    .rooms
+                    .into_iter()
+                    .find(|room| room.room_id == room_id));
+            }
+            let line = String::from_utf8_lossy(&line);
+            if let Some((command, reason)) =
+                parse_limit_error(&line).or_else(|| parse_refusal(&line))
+            {
+                return Err(std::io::Error::other(format!(
+                    "the server refused {}: {}",
+                    command, reason
+                )));
+            }
+        }
+    }
+}
+
+fn play_tcp(
+    stream: &mut TcpStream,
+    mouse: &MouseEngine,
+    trapper: &TrapperEngine,
+    max_turns: u32,
+) -> std::io::Result<(Outcome, u32)> {
+    send(stream, "create_single_room ")?;
+    let room_id: u32 = read_reply(stream)?
+        .parse()
+        .map_err(|_| std::io::Error::other("create_single_room gave no room id"))?;
+    let room_name = match fetch_room(stream, room_id)? {
+        Some(room) => room.room_name,
+        None => return Err(std::io::Error::other("room was not created")),
+    };
+    // A difficulty starts the game. The runner moves the mouse by hand and
+    // never asks the AI, so which one it gets does not matter.
+    let difficulty = match mouse.difficulty() {
+        Some(_) => mouse.name(),
+        None => MouseEngine::Easy.name(),
+    };
+    send(
+        stream,
+        &format!("set_difficulty {} {} ", difficulty, room_name),
+    )?;
+
+    let mut room = match fetch_room(stream, room_id)? {
+        Some(room) => room,
+        None => return Err(std::io::Error::other("room disappeared before the game")),
+    };
+    let mut result = (Outcome::Unfinished, max_turns);
+
+    for turn in 1..=max_turns {
+        let (x, y) = match trapper.pick(&room) {
+            Some(hex) => hex,
+            None => {
+                result = (Outcome::MouseWon, turn);
+                break;
+            }
+        };
+        send(stream, &format!("place_trap {} {} {} ", room_id, x, y))?;
+        // The server ends the game as soon as the wall traps the mouse.
+        room.walls.push((x, y));
+        if room.mouse_trapped() {
+            result = (Outcome::TrapperWon, turn);
+            break;
+        }
+
+        match mouse {
+            MouseEngine::Runner => {
+                if let Some((x, y)) = mouse.pick(&room) {
+                    send(stream, &format!("move_mouse {} {} {} ", room_id, x, y))?;
+                }
+            }
+            _ => send(stream, &format!("AI {} ", room_id))?,
+        }
+
+        room = match fetch_room(stream, room_id)? {
+            Some(room) => room,
+            None => return Err(std::io::Error::other("room disappeared mid-game")),
+        };
+        if room.mouse_trapped()
+            || room.winner == Some(Turn::TrapperPlayer)
+            || room.turn == Turn::MousePlayer
+        {
+            result = (Outcome::TrapperWon, turn);
+            break;
+        }
+        if room.mouse_escaped() {
+            result = (Outcome::MouseWon, turn);
+            break;
+        }
+    }
+
+    send(stream, &format!("delete_room {} ", room_id))?;
+    Ok(result)
+}
+
+/// 95% Wilson score interval for `wins` out of `games`.
+fn wilson_interval(wins: u32, games: u32) -> (f64, f64) {
+    if games == 0 {
+        return (0.0, 0.0);
+    }
+    let z = 1.96;
+    let n = games as f64;
+    let p = wins as f64 / n;
+    let denominator = 1.0 + z * z / n;
+    let centre = p + z * z / (2.0 * n);
+    let margin = z * ((p * (1.0 - p) + z * z / (4.0 * n)) / n).sqrt();
+    (
+        ((centre - margin) / denominator).max(0.0),
+        ((centre + margin) / denominator).min(1.0),
+    )
+}
+
+fn summarize(mouse: &MouseEngine, trapper: &TrapperEngine, results: &[(Outcome, u32)]) -> PairingSummary {
+    let games = results.len() as u32;
+    let count = |outcome: Outcome| results.iter().filter(|(o, _)| *o == outcome).count() as u32;
+    let mouse_wins = count(Outcome::MouseWon);
+    let trapper_wins = count(Outcome::TrapperWon);
+    let rate = |wins: u32| if games == 0 { 0.0 } else { wins as f64 / games as f64 };
+    let (mouse_low, mouse_high) = wilson_interval(mouse_wins, games);
+    let (trapper_low, trapper_high) = wilson_interval(trapper_wins, games);
+    let total_length: u32 = results.iter().map(|(_, length)| length).sum();
+
+    PairingSummary {
+        mouse: mouse.name().to_string(),
+        trapper: trapper.name().to_string(),
+        games,
+        mouse_wins,
+        trapper_wins,
+        unfinished: count(Outcome::Unfinished),
+        mouse_win_rate: rate(mouse_wins),
+        mouse_win_rate_ci_low: mouse_low,
+        mouse_win_rate_ci_high: mouse_high,
+        trapper_win_rate: rate(trapper_wins),
+        trapper_win_rate_ci_low: trapper_low,
+        trapper_win_rate_ci_high: trapper_high,
+        avg_game_length: if games == 0 { 0.0 } else { total_length as f64 / games as f64 },
+    }
+}
+
+fn print_csv(summaries: &[PairingSummary]) {
+    println!(
+        "mouse,trapper,games,mouse_wins,trapper_wins,unfinished,mouse_win_rate,mouse_ci_low,mouse_ci_high,trapper_win_rate,trapper_ci_low,trapper_ci_high,avg_game_length"
+    );
+    for s in summaries {
+        println!(
+            "{},{},{},{},{},{},{:.4},{:.4},{:.4},{:.4},{:.4},{:.4},{:.2}",
+            s.mouse,
+            s.trapper,
+            s.games,
+            s.mouse_wins,
+            s.trapper_wins,
+            s.unfinished,
+            s.mouse_win_rate,
+            s.mouse_win_rate_ci_low,
+            s.mouse_win_rate_ci_high,
+            s.trapper_win_rate,
+            s.trapper_win_rate_ci_low,
+            s.trapper_win_rate_ci_high,
+            s.avg_game_length
+        );
+    }
+}
+
+fn usage() -> String {
+    [
+        "usage: tournament [options]",
+        "  --games N              games per pairing (default 100)",
+        "  --mouse a,b            mouse engines: easy, medium, runner (default easy,medium,runner)",
+        "  --trapper a,b          trapper engines: random, blocker (default random,blocker)",
+        "  --tcp ADDR             play through a running server instead of in-process",
+        "                         (start that server with --no-limits)",
+        "  --format csv|json      summary format (default csv)",
+        "  --max-turns N          turns before a game counts as unfinished (default 121)",
+    ]
+    .join("\n")
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        games: 100,
+        mice: vec![MouseEngine::Easy, MouseEngine::Medium, MouseEngine::Runner],
+        trappers: vec![TrapperEngine::Random, TrapperEngine::Blocker],
+        transport: Transport::Local,
+        format: "csv".to_string(),
+        max_turns: 121,
+    };
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut i = 0;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        if flag == "--help" {
+            return Err(usage());
+        }
+        let value = args
+            .get(i + 1)
+            .ok_or_else(|| format!("missing value for {}\n{}", flag, usage()))?;
+        match flag {
+            "--games" => options.games = value.parse().map_err(|_| "--games expects a number")?,
+            "--max-turns" => {
+                options.max_turns = value.parse().map_err(|_| "--max-turns expects a number")?
+            }
+            "--mouse" => {
+                options.mice = value
+                    .split(',')
+                    .map(MouseEngine::parse)
+                    .collect::<Result<_, _>>()?
+            }
+            "--trapper" => {
+                options.trappers = value
+                    .split(',')
+                    .map(TrapperEngine::parse)
+                    .collect::<Result<_, _>>()?
+            }
+            "--tcp" => options.transport = Transport::Tcp(value.clone()),
+            "--format" => match value.as_str() {
+                "csv" | "json" => options.format = value.clone(),
+                _ => return Err(format!("unknown format '{}'", value)),
+            },
+            _ => return Err(format!("unknown option '{}'\n{}", flag, usage())),
+        }
+        i += 2;
+    }
+    Ok(options)
+}
+
+fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let mut stream = match &options.transport {
+        Transport::Local => None,
+        Transport::Tcp(addr) => match TcpStream::connect(addr).and_then(|mut stream| {
+            hello(&mut stream)?;
+            Ok(stream)
+        }) {
+            Ok(stream) => {
+                let _ = stream.set_nodelay(true);
+                Some(stream)
+            }
+            Err(e) => {
+                eprintln!("ERR:could not connect to {} {}", addr, e);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let mut summaries = Vec::new();
+    for mouse in &options.mice {
+        for trapper in &options.trappers {
+            let mut results = Vec::new();
+            for game in 0..options.games {
+                let result = match stream.as_mut() {
+                    None => play_local(mouse, trapper, options.max_turns),
+                    Some(stream) => {
+                        let game_name = format!(
+                            "tournament-{}-{}-{}-{}",
+                            std::process::id(),
+                            mouse.name(),
+                            trapper.name(),
+                            game
+                        );
+                        match play_tcp(stream, mouse, trapper, options.max_turns) {
+                            Ok(result) => result,
+                            Err(e) => {
+                                eprintln!("ERR:game {} failed {}", game_name, e);
+                                std::process::exit(1);
+                            }
+                        }
+                    }
+                };
+                results.push(result);
+            }
+            summaries.push(summarize(mouse, trapper, &results));
+        }
+    }
+
+    if options.format == "json" {
+        println!("{}", serde_json::to_string_pretty(&summaries).unwrap());
+    } else {
+        print_csv(&summaries);
+    }
+}